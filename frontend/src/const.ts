@@ -1,2 +1,3 @@
 export const BUILD_URL = "/api/build";
 export const RUN_URL = "/api/run";
+export const SNIPPETS_URL = "/api/snippets";
@@ -1,5 +1,7 @@
 import { writable } from "svelte/store";
-import type { Fail, ServerResponse, Success } from "./vite-env";
+import type { Fail, ServerResponse, StoredResult, Success } from "./vite-env";
 
 export const response = writable<ServerResponse<Success | Fail>>(null);
 export const error = writable<string>("");
+// Result saved with the shared snippet being viewed.
+export const stored = writable<StoredResult>(null);
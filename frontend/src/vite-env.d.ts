@@ -1,7 +1,35 @@
 /// <reference types="svelte" />
 /// <reference types="vite/client" />
 
-export type Success = { elapsed: number; output?: string };
+export type CacheStatus = "hit" | "miss" | "disabled";
+export type ModuleInfo = { elapsed: number; cache: CacheStatus };
+export type ResourceUsage = {
+  elapsed: number;
+  peak_memory: number;
+  memory_grow_events: number;
+  stdout_bytes: number;
+  fuel_consumed?: number;
+};
+export type Success = {
+  elapsed: number;
+  output?: string;
+  stderr?: string;
+  truncated: boolean;
+  cache: CacheStatus;
+  module?: ModuleInfo;
+  usage?: ResourceUsage;
+};
 export type Fail = string;
 export type ResponseType = "Success" | "Error";
-export type ServerResponse<Data extends Success | Fail> = { type: ResponseType; data: Data };
+export type ServerResponse<Data extends Success | Fail> = {
+  type: ResponseType;
+  data: Data;
+  // Only on failed builds, successful ones report it in `data`.
+  cache?: CacheStatus;
+  // Only when the program panicked or exited with an error.
+  exit_code?: number;
+};
+export type StoredResult = {
+  toolchain: string;
+  response: ServerResponse<Success | Fail>;
+};
@@ -0,0 +1,214 @@
+use crate::handler::{BuildOptions, CrateType};
+use crate::snippets::Snippet;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Edition rustc falls back to, since the playground doesn't pass `--edition`.
+pub const EDITION: &str = "2015";
+const METADATA_FILE: &str = "playground.json";
+/// Bytes unpacked from an archive at most, however well it compresses.
+const MAX_UNPACKED_SIZE: u64 = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("the archive could not be read: {0}")]
+    Unreadable(#[from] std::io::Error),
+    #[error("the archive metadata is invalid: {0}")]
+    InvalidMetadata(#[from] serde_json::Error),
+    #[error("the archive has no src/main.rs or src/lib.rs")]
+    MissingSource,
+    #[error("the source code is not valid UTF-8")]
+    InvalidSource,
+}
+
+/// Everything needed to build the snippet the same way outside the
+/// playground, stored as `playground.json` next to `Cargo.toml`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub edition: String,
+    /// `stable`, `beta` or `nightly`.
+    pub channel: String,
+    pub toolchain: String,
+    #[serde(default)]
+    pub options: BuildOptions,
+}
+
+/// Release channel of a toolchain as printed by `rustc --version`.
+fn channel(toolchain: &str) -> &'static str {
+    if toolchain.contains("-nightly") {
+        "nightly"
+    } else if toolchain.contains("-beta") {
+        "beta"
+    } else {
+        "stable"
+    }
+}
+
+fn source_file(crate_type: CrateType) -> &'static str {
+    match crate_type {
+        CrateType::Bin => "src/main.rs",
+        CrateType::Lib | CrateType::ProcMacro => "src/lib.rs",
+    }
+}
+
+fn cargo_manifest(crate_type: CrateType) -> String {
+    let mut manifest =
+        format!("[package]\nname = \"playground\"\nversion = \"0.1.0\"\nedition = \"{EDITION}\"\n");
+    if crate_type == CrateType::ProcMacro {
+        manifest.push_str("\n[lib]\nproc-macro = true\n");
+    }
+    manifest.push_str("\n[dependencies]\n");
+    manifest
+}
+
+fn append_file(
+    archive: &mut tar::Builder<impl std::io::Write>,
+    path: impl AsRef<Path>,
+    contents: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    archive.append_data(&mut header, path, contents)
+}
+
+/// Packs the snippet into a gzipped tarball holding a Cargo project in a
+/// directory named after the snippet.
+pub fn export(id: &str, snippet: &Snippet, toolchain: &str) -> anyhow::Result<Vec<u8>> {
+    let crate_type = snippet.options.crate_type;
+    let metadata = Metadata {
+        id: Some(id.to_owned()),
+        edition: EDITION.to_owned(),
+        channel: channel(toolchain).to_owned(),
+        toolchain: toolchain.to_owned(),
+        options: snippet.options.clone(),
+    };
+
+    let root = PathBuf::from(format!("playground-{id}"));
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut archive = tar::Builder::new(encoder);
+    append_file(
+        &mut archive,
+        root.join("Cargo.toml"),
+        cargo_manifest(crate_type).as_bytes(),
+    )?;
+    append_file(
+        &mut archive,
+        root.join(source_file(crate_type)),
+        snippet.code.as_bytes(),
+    )?;
+    append_file(
+        &mut archive,
+        root.join(METADATA_FILE),
+        &serde_json::to_vec_pretty(&metadata)?,
+    )?;
+    Ok(archive.into_inner()?.finish()?)
+}
+
+/// Reads a snippet back from an archive made by [`export`] or packed from a
+/// Cargo project by hand. Files other than the source and metadata are ignored.
+pub fn import(bytes: &[u8]) -> Result<Snippet, ArchiveError> {
+    let unpacked = GzDecoder::new(bytes).take(MAX_UNPACKED_SIZE);
+    let mut archive = tar::Archive::new(unpacked);
+
+    let mut metadata: Option<Metadata> = None;
+    let mut main = None;
+    let mut lib = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        // Files may be in a top-level directory, whatever it is called.
+        let nested = path.iter().skip(1).collect::<PathBuf>();
+        let is = |name: &str| path == Path::new(name) || nested == Path::new(name);
+        let slot = if is(METADATA_FILE) {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            metadata = Some(serde_json::from_slice(&contents)?);
+            continue;
+        } else if is("src/main.rs") {
+            &mut main
+        } else if is("src/lib.rs") {
+            &mut lib
+        } else {
+            continue;
+        };
+        let mut code = Vec::new();
+        entry.read_to_end(&mut code)?;
+        *slot = Some(String::from_utf8(code).map_err(|_| ArchiveError::InvalidSource)?);
+    }
+
+    // Without metadata the crate type follows from the source file.
+    let options = match metadata {
+        Some(metadata) => metadata.options,
+        None if main.is_none() && lib.is_some() => BuildOptions {
+            crate_type: CrateType::Lib,
+        },
+        None => BuildOptions::default(),
+    };
+    let code = match options.crate_type {
+        CrateType::Bin => main,
+        CrateType::Lib | CrateType::ProcMacro => lib,
+    };
+    Ok(Snippet {
+        code: code.ok_or(ArchiveError::MissingSource)?,
+        options,
+        parent: None,
+        forked_from: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::toolchain_version;
+    use crate::snippets::{SnippetStore, SnippetsConfig};
+
+    #[tokio::test]
+    async fn export_and_import_snippets() -> anyhow::Result<()> {
+        let snippet = Snippet {
+            code: "pub fn exported() {}".into(),
+            options: BuildOptions {
+                crate_type: CrateType::Lib,
+            },
+            parent: None,
+            forked_from: None,
+        };
+        let store = SnippetStore::new(SnippetsConfig {
+            dir: tempfile::tempdir()?.into_path(),
+        })?;
+        let id = store.put(&snippet).await?;
+
+        let exported = export(&id, &snippet, &toolchain_version().await)?;
+        let imported = import(&exported)?;
+        assert_eq!(imported, snippet);
+        assert_eq!(store.put(&imported).await?, id);
+
+        // Projects packed by hand have no metadata or top-level directory.
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        let code = b"fn main() {}";
+        header.set_size(code.len() as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, "src/main.rs", &code[..])?;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &builder.into_inner()?)?;
+        let imported = import(&encoder.finish()?)?;
+        assert_eq!(imported.code, "fn main() {}");
+        assert_eq!(imported.options.crate_type, CrateType::Bin);
+
+        assert!(matches!(
+            import(b"not an archive"),
+            Err(ArchiveError::Unreadable(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::hash::{Hash, Hasher};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+use tokio::fs;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use wasmtime::{Engine, Module};
+
+const RESULT_FILE: &str = "result.json";
+const ARTIFACT_FILE: &str = "artifact";
+const MODULE_FILE: &str = "module.cwasm";
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// The user's own cache directory rather than a shared one like `/tmp`, where
+/// anyone could create the directory first.
+fn default_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("typerust")
+}
+
+fn default_max_size() -> u64 {
+    bytesize::MB * 512
+}
+
+fn default_max_age() -> u64 {
+    60 * 60 * 24
+}
+
+/// Read from `BUILD_CACHE_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct CacheConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+    /// Total size of all entries in bytes.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Seconds an entry may stay unused before it is evicted.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Disabled,
+}
+
+impl CacheStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Miss => "miss",
+            CacheStatus::Disabled => "disabled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey(String);
+
+/// Outcome of a compilation as stored on disk. The artifact of a successful
+/// build is kept next to it and copied in and out by path.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum CachedBuild {
+    Success,
+    Failure(String),
+}
+
+/// Content-addressed store of compiled artifacts and diagnostics, as well as
+/// wasmtime modules precompiled from those artifacts.
+pub struct BuildCache {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: Duration,
+    toolchain: String,
+    evicting: Mutex<()>,
+}
+
+impl BuildCache {
+    pub async fn new(config: CacheConfig) -> anyhow::Result<Self> {
+        create_private_dir(&config.dir)?;
+
+        let output = Command::new("rustc").arg("-vV").output().await?;
+        if !output.status.success() {
+            anyhow::bail!("failed to query rustc version");
+        }
+        let toolchain = String::from_utf8(output.stdout)?;
+
+        Ok(Self {
+            dir: config.dir,
+            max_size: config.max_size,
+            max_age: Duration::from_secs(config.max_age),
+            toolchain,
+            evicting: Mutex::new(()),
+        })
+    }
+
+    pub fn key(&self, code: &str, rustc_args: &[&str]) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(self.toolchain.as_bytes());
+        for arg in rustc_args {
+            hasher.update([0]);
+            hasher.update(arg.as_bytes());
+        }
+        hasher.update([0]);
+        hasher.update(code.as_bytes());
+        CacheKey(hex::encode(hasher.finalize()))
+    }
+
+    /// Serialized modules are only loadable by an engine with compatible
+    /// settings, so the engine configuration is part of the key.
+    pub fn module_key(&self, engine: &Engine, wasm: &[u8]) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(b"module");
+        engine
+            .precompile_compatibility_hash()
+            .hash(&mut Sha256Hasher(&mut hasher));
+        hasher.update(wasm);
+        CacheKey(hex::encode(hasher.finalize()))
+    }
+
+    fn entry_dir(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(&key.0)
+    }
+
+    /// Looks up a previous build, copying its artifact to `artifact` on success.
+    pub async fn get(&self, key: &CacheKey, artifact: impl AsRef<Path>) -> Option<CachedBuild> {
+        let entry = self.entry_dir(key);
+        let result_file = entry.join(RESULT_FILE);
+        let contents = fs::read(&result_file).await.ok()?;
+        let build: CachedBuild = serde_json::from_slice(&contents).ok()?;
+
+        if let CachedBuild::Success = build {
+            // The entry may be evicted concurrently, in which case this is just a miss.
+            fs::copy(entry.join(ARTIFACT_FILE), artifact).await.ok()?;
+        }
+
+        if let Err(e) = touch(&result_file).await {
+            tracing::warn!("failed to update cache entry access time: {e}");
+        }
+
+        Some(build)
+    }
+
+    pub async fn put(
+        &self,
+        key: &CacheKey,
+        build: &CachedBuild,
+        artifact: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let staging = self.staging_dir()?;
+
+        if let CachedBuild::Success = build {
+            fs::copy(artifact, staging.path().join(ARTIFACT_FILE)).await?;
+        }
+        fs::write(staging.path().join(RESULT_FILE), serde_json::to_vec(build)?).await?;
+
+        self.commit(key, staging).await
+    }
+
+    pub async fn get_module(&self, engine: &Engine, key: &CacheKey) -> Option<Module> {
+        let module_file = self.entry_dir(key).join(MODULE_FILE);
+        let engine = engine.clone();
+        let path = module_file.clone();
+        // SAFETY: the file was produced by `Module::serialize` in `put_module` and
+        // entries are never modified after being moved into place.
+        let module =
+            tokio::task::spawn_blocking(move || unsafe { Module::deserialize_file(&engine, path) })
+                .await
+                .ok()?
+                .ok()?;
+
+        if let Err(e) = touch(&module_file).await {
+            tracing::warn!("failed to update cache entry access time: {e}");
+        }
+
+        Some(module)
+    }
+
+    pub async fn put_module(&self, key: &CacheKey, module: &Module) -> anyhow::Result<()> {
+        let staging = self.staging_dir()?;
+        fs::write(staging.path().join(MODULE_FILE), module.serialize()?).await?;
+        self.commit(key, staging).await
+    }
+
+    fn staging_dir(&self) -> std::io::Result<TempDir> {
+        tempfile::Builder::new()
+            .prefix(".staging-")
+            .tempdir_in(&self.dir)
+    }
+
+    async fn commit(&self, key: &CacheKey, staging: TempDir) -> anyhow::Result<()> {
+        // Renaming is atomic, so readers never observe a partially written entry.
+        // If another request stored the same key first, keep theirs.
+        let staging = staging.into_path();
+        if fs::rename(&staging, self.entry_dir(key)).await.is_err() {
+            fs::remove_dir_all(&staging).await?;
+        }
+
+        self.evict().await;
+        Ok(())
+    }
+
+    async fn evict(&self) {
+        let Ok(_guard) = self.evicting.try_lock() else {
+            return;
+        };
+
+        let dir = self.dir.clone();
+        let max_size = self.max_size;
+        let max_age = self.max_age;
+        let result =
+            tokio::task::spawn_blocking(move || evict_entries(&dir, max_size, max_age)).await;
+
+        match result {
+            Ok(Ok(0)) => {}
+            Ok(Ok(evicted)) => tracing::info!(evicted, "evicted build cache entries"),
+            Ok(Err(e)) => tracing::warn!("failed to evict build cache entries: {e}"),
+            Err(e) => tracing::warn!("build cache eviction task failed: {e}"),
+        }
+    }
+}
+
+/// Lets types implementing [`Hash`] feed SHA-256, which unlike `DefaultHasher`
+/// gives the same result with every Rust release.
+struct Sha256Hasher<'a>(&'a mut Sha256);
+
+impl Hasher for Sha256Hasher<'_> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("the digest is read from the wrapped hasher")
+    }
+}
+
+/// Precompiled modules are loaded from the cache as native code, so nobody
+/// but the server's own user may be able to put files there.
+fn create_private_dir(dir: &Path) -> anyhow::Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    let metadata = std::fs::metadata(dir)?;
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid {
+        anyhow::bail!("cache directory {dir:?} is owned by another user");
+    }
+    if metadata.mode() & 0o022 != 0 {
+        anyhow::bail!("cache directory {dir:?} is writable by other users");
+    }
+    Ok(())
+}
+
+async fn touch(path: &Path) -> std::io::Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(path).await?;
+    file.into_std().await.set_modified(SystemTime::now())
+}
+
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+/// Entries are touched when read, so the newest file marks the last use.
+fn read_entry(path: PathBuf) -> std::io::Result<Entry> {
+    let mut size = 0;
+    let mut last_used = SystemTime::UNIX_EPOCH;
+    for file in std::fs::read_dir(&path)? {
+        let metadata = file?.metadata()?;
+        size += metadata.len();
+        last_used = last_used.max(metadata.modified()?);
+    }
+    Ok(Entry {
+        path,
+        size,
+        last_used,
+    })
+}
+
+/// Removes entries unused for longer than `max_age`, then the least recently
+/// used ones until the total size fits into `max_size`.
+fn evict_entries(dir: &Path, max_size: u64, max_age: Duration) -> std::io::Result<usize> {
+    let mut entries = Vec::new();
+    for dir_entry in std::fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        if dir_entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if let Ok(entry) = read_entry(dir_entry.path()) {
+            entries.push(entry);
+        }
+    }
+
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let now = SystemTime::now();
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut evicted = 0;
+
+    for entry in entries {
+        let age = now.duration_since(entry.last_used).unwrap_or_default();
+        if age <= max_age && total_size <= max_size {
+            break;
+        }
+        std::fs::remove_dir_all(&entry.path)?;
+        total_size -= entry.size;
+        evicted += 1;
+    }
+
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn config(dir: &Path) -> CacheConfig {
+        CacheConfig {
+            enabled: true,
+            dir: dir.to_path_buf(),
+            max_size: bytesize::MB,
+            max_age: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_shared_cache_dir() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let dir = root.path().join("cache");
+        BuildCache::new(config(&dir)).await?;
+        let mode = std::fs::metadata(&dir)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777))?;
+        assert!(BuildCache::new(config(&dir)).await.is_err());
+        Ok(())
+    }
+}
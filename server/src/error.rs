@@ -1,8 +1,16 @@
+use crate::scheduler::QueueFull;
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, SandboxError>;
 
 #[derive(Error, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum SandboxError {
     #[error("out of memory error")]
     OOM,
@@ -10,6 +18,12 @@ pub enum SandboxError {
     Internal(String),
     #[error("timeout error")]
     Timeout,
+    #[error("no sandbox available")]
+    Busy,
+    #[error("job queue is full")]
+    QueueFull,
+    #[error("fuel exhausted error")]
+    FuelExhausted,
 }
 
 impl From<anyhow::Error> for SandboxError {
@@ -19,3 +33,33 @@ impl From<anyhow::Error> for SandboxError {
             .unwrap_or(SandboxError::Internal(message))
     }
 }
+
+impl From<QueueFull> for SandboxError {
+    fn from(_: QueueFull) -> Self {
+        SandboxError::QueueFull
+    }
+}
+
+/// Structured body of requests turned away before reaching a handler.
+#[derive(Serialize, Debug)]
+pub struct Rejection {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub error: &'static str,
+    pub message: String,
+    /// Seconds until the next request is allowed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(&self)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
@@ -1,20 +1,25 @@
+use crate::cache::{BuildCache, CacheStatus, CachedBuild};
 use crate::error::Result;
-use crate::wasm::execute_wasm;
+use crate::limits::RequestedLimits;
+use crate::output::{OutputKind, OutputSink};
+use crate::scheduler::ClientId;
+use crate::wasm::{execute_wasm, load_module, Execution, GuestIo, ResourceUsage};
 use crate::State;
 use anyhow::bail;
-use serde::Serialize;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tempfile::TempDir;
 use tokio::fs;
 use tokio::process::Command;
+use tokio::sync::{mpsc, OnceCell};
 use tokio::time::{Duration, Instant};
 use tracing::instrument;
 
 const CRATE_NAME: &str = "playground";
 
 struct Compiler {
-    #[allow(dead_code)]
     tempdir: TempDir,
     output_dir: PathBuf,
     input_file: PathBuf,
@@ -26,6 +31,82 @@ macro_rules! add_ext {
     };
 }
 
+/// Kind of crate the submitted code is compiled as.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrateType {
+    #[default]
+    Bin,
+    Lib,
+    /// Compiled for the host since proc macros can't target WASM. Build-only.
+    ProcMacro,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildOptions {
+    #[serde(default)]
+    pub crate_type: CrateType,
+}
+
+/// What rustc is asked to produce from the playground source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Executable,
+    Library,
+    ProcMacro,
+    /// Library code compiled together with the libtest harness so it can be run.
+    TestHarness,
+}
+
+impl Target {
+    fn for_build(crate_type: CrateType) -> Self {
+        match crate_type {
+            CrateType::Bin => Target::Executable,
+            CrateType::Lib => Target::Library,
+            CrateType::ProcMacro => Target::ProcMacro,
+        }
+    }
+
+    fn for_run(crate_type: CrateType) -> Option<Self> {
+        match crate_type {
+            CrateType::Bin => Some(Target::Executable),
+            CrateType::Lib => Some(Target::TestHarness),
+            CrateType::ProcMacro => None,
+        }
+    }
+
+    fn rustc_args(self) -> &'static [&'static str] {
+        match self {
+            Target::Executable => &["--target", "wasm32-wasip1"],
+            Target::Library => &["--crate-type", "lib", "--target", "wasm32-wasip1"],
+            Target::ProcMacro => &["--crate-type", "proc-macro", "--extern", "proc_macro"],
+            Target::TestHarness => &["--test", "--target", "wasm32-wasip1"],
+        }
+    }
+
+    /// Arguments the built program is run with.
+    fn run_args(self) -> &'static [&'static str] {
+        match self {
+            // Test output is captured by default and lost when a failing test
+            // aborts the whole run, taking its panic message with it.
+            Target::TestHarness => &[CRATE_NAME, "--nocapture"],
+            _ => &[CRATE_NAME],
+        }
+    }
+
+    fn artifact_name(self) -> String {
+        match self {
+            Target::Executable | Target::TestHarness => add_ext!(CRATE_NAME, "wasm"),
+            Target::Library => add_ext!(format!("lib{CRATE_NAME}"), "rlib"),
+            Target::ProcMacro => format!(
+                "{}{CRATE_NAME}{}",
+                std::env::consts::DLL_PREFIX,
+                std::env::consts::DLL_SUFFIX
+            ),
+        }
+    }
+}
+
 type OutputText = String;
 type ExecutablePath = PathBuf;
 
@@ -80,17 +161,21 @@ impl Compiler {
         Ok(())
     }
 
-    async fn compile(&self, code: String) -> anyhow::Result<BuildResult> {
+    async fn compile(&self, code: String, target: Target) -> anyhow::Result<BuildResult> {
         self.write_source_code(code).await?;
 
         let start = Instant::now();
+        // Run from inside the tempdir so diagnostics only mention the relative
+        // source path and are identical across requests.
         let mut cmd = Command::new("rustc");
         let cmd = cmd
+            .current_dir(self.tempdir.path())
             .arg("--out-dir")
             .arg(&self.output_dir)
-            .arg("--target")
-            .arg("wasm32-wasip1")
-            .arg(&self.input_file);
+            .args(target.rustc_args())
+            .arg(add_ext!(CRATE_NAME, "rs"))
+            // Stops compiling if the request is dropped.
+            .kill_on_drop(true);
         let output = cmd.output().await;
 
         if output.is_err() {
@@ -109,35 +194,390 @@ impl Compiler {
             return Ok(BuildResult::Failure(string));
         }
 
-        let filename = add_ext!(CRATE_NAME, "wasm");
-        let executable = self.output_dir.join(filename);
+        let executable = self.output_dir.join(target.artifact_name());
 
         Ok(BuildResult::Success {
             executable,
             elapsed,
         })
     }
+
+    async fn compile_cached(
+        &self,
+        code: String,
+        target: Target,
+        cache: Option<&BuildCache>,
+    ) -> anyhow::Result<(BuildResult, CacheStatus)> {
+        let cache = match cache {
+            Some(cache) => cache,
+            None => {
+                let result = self.compile(code, target).await?;
+                return Ok((result, record_cache_status(CacheStatus::Disabled)));
+            }
+        };
+
+        let key = cache.key(&code, target.rustc_args());
+        let executable = self.output_dir.join(target.artifact_name());
+
+        let start = Instant::now();
+        if let Some(cached) = cache.get(&key, &executable).await {
+            let result = match cached {
+                CachedBuild::Success => BuildResult::Success {
+                    elapsed: start.elapsed(),
+                    executable,
+                },
+                CachedBuild::Failure(output) => BuildResult::Failure(output),
+            };
+            return Ok((result, record_cache_status(CacheStatus::Hit)));
+        }
+
+        let result = self.compile(code, target).await?;
+        let cached = match &result {
+            BuildResult::Success { .. } => CachedBuild::Success,
+            BuildResult::Failure(output) => CachedBuild::Failure(output.clone()),
+        };
+        if let Err(e) = cache.put(&key, &cached, &executable).await {
+            tracing::warn!("failed to store build in cache: {e}");
+        }
+
+        Ok((result, record_cache_status(CacheStatus::Miss)))
+    }
 }
 
-#[derive(Serialize)]
+fn record_cache_status(status: CacheStatus) -> CacheStatus {
+    tracing::Span::current().record("build.cache", &status.as_str());
+    status
+}
+
+/// Cranelift compilation of the built artifact, reported separately from rustc.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ModuleInfo {
+    elapsed: f32,
+    cache: CacheStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Success {
     elapsed: f32,
     output: Option<String>,
+    /// What the program wrote to stderr, if it was run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    /// Set when the program was stopped for exceeding the output limit.
+    truncated: bool,
+    cache: CacheStatus,
+    module: Option<ModuleInfo>,
+    usage: Option<ResourceUsage>,
 }
 
-#[derive(Serialize)]
-#[serde(tag = "type", content = "data")]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "WireResponse", into = "WireResponse")]
 pub enum HandlerResponse {
+    Success(Success),
+    /// The code failed to build with these diagnostics.
+    BuildFailed {
+        output: String,
+        cache: CacheStatus,
+    },
+    /// The program panicked or exited with an error. `output` has everything
+    /// it printed followed by the exit code.
+    RuntimeFailed {
+        output: String,
+        exit_code: i32,
+    },
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+enum ResponseData {
     Success(Success),
     Error(String),
 }
 
+/// How a [`HandlerResponse`] is sent. Failed builds and runs are errors like
+/// any other, with the cache status of their diagnostics or the exit code of
+/// the program next to them.
+#[derive(Serialize, Deserialize)]
+struct WireResponse {
+    #[serde(flatten)]
+    data: ResponseData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+}
+
+impl From<HandlerResponse> for WireResponse {
+    fn from(response: HandlerResponse) -> Self {
+        let (data, cache, exit_code) = match response {
+            HandlerResponse::Success(success) => (ResponseData::Success(success), None, None),
+            HandlerResponse::BuildFailed { output, cache } => {
+                (ResponseData::Error(output), Some(cache), None)
+            }
+            HandlerResponse::RuntimeFailed { output, exit_code } => {
+                (ResponseData::Error(output), None, Some(exit_code))
+            }
+            HandlerResponse::Error(message) => (ResponseData::Error(message), None, None),
+        };
+        WireResponse {
+            data,
+            cache,
+            exit_code,
+        }
+    }
+}
+
+impl From<WireResponse> for HandlerResponse {
+    fn from(response: WireResponse) -> Self {
+        match (response.data, response.cache, response.exit_code) {
+            (ResponseData::Success(success), ..) => HandlerResponse::Success(success),
+            (ResponseData::Error(output), Some(cache), _) => {
+                HandlerResponse::BuildFailed { output, cache }
+            }
+            (ResponseData::Error(output), None, Some(exit_code)) => {
+                HandlerResponse::RuntimeFailed { output, exit_code }
+            }
+            (ResponseData::Error(message), None, None) => HandlerResponse::Error(message),
+        }
+    }
+}
+
+impl HandlerResponse {
+    /// What the user is shown, either the program's output or the error.
+    pub fn text(&self) -> &str {
+        match self {
+            HandlerResponse::Success(success) => success.output.as_deref().unwrap_or_default(),
+            HandlerResponse::BuildFailed { output, .. }
+            | HandlerResponse::RuntimeFailed { output, .. } => output,
+            HandlerResponse::Error(message) => message,
+        }
+    }
+
+    /// Whether running the same code again gives the same response. Errors
+    /// like timeouts also depend on how busy the server was.
+    pub fn is_reproducible(&self) -> bool {
+        !matches!(self, HandlerResponse::Error(_))
+    }
+
+    /// Whether both responses show the user the same thing, regardless of timings.
+    pub fn same_output(&self, other: &HandlerResponse) -> bool {
+        match (self, other) {
+            (HandlerResponse::Success(a), HandlerResponse::Success(b)) => {
+                a.output == b.output && a.stderr == b.stderr && a.truncated == b.truncated
+            }
+            (HandlerResponse::Success(_), _) | (_, HandlerResponse::Success(_)) => false,
+            _ => self.text() == other.text(),
+        }
+    }
+}
+
+static TOOLCHAIN: OnceCell<String> = OnceCell::const_new();
+
+/// Version of the rustc building submitted code, as printed by `rustc --version`.
+pub async fn toolchain_version() -> String {
+    let version = TOOLCHAIN.get_or_init(|| async {
+        match Command::new("rustc").arg("--version").output().await {
+            Ok(output) if output.status.success() => {
+                String::from_utf8_lossy(&output.stdout).trim().to_owned()
+            }
+            _ => {
+                tracing::warn!("failed to query rustc version");
+                "unknown".to_owned()
+            }
+        }
+    });
+    version.await.clone()
+}
+
+/// Progress of a streamed run, sent to the client as it happens.
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum RunEvent {
+    /// Position of the job in the queue for the next slot, starting at 1.
+    Queued {
+        position: usize,
+    },
+    Compiling,
+    Compiled {
+        elapsed: f32,
+        cache: CacheStatus,
+    },
+    Running,
+    Stdout(String),
+    Stderr(String),
+    Done(HandlerResponse),
+}
+
+pub type RunEvents = mpsc::UnboundedSender<RunEvent>;
+
+fn emit(events: Option<&RunEvents>, event: RunEvent) {
+    if let Some(events) = events {
+        // The client may be gone already, in which case nobody cares.
+        let _ = events.send(event);
+    }
+}
+
+fn output_sink(events: RunEvents) -> OutputSink {
+    Arc::new(move |kind, bytes| {
+        // Chunks may split multi-byte characters, clients get replacements then.
+        let text = String::from_utf8_lossy(bytes).into_owned();
+        let event = match kind {
+            OutputKind::Stdout => RunEvent::Stdout(text),
+            OutputKind::Stderr => RunEvent::Stderr(text),
+        };
+        let _ = events.send(event);
+    })
+}
+
+/// Marks the current span as cancelled if dropped before [`finish`] is called.
+///
+/// [`finish`]: CancelGuard::finish
+struct CancelGuard {
+    span: tracing::Span,
+    finished: bool,
+}
+
+impl CancelGuard {
+    fn new() -> Self {
+        Self {
+            span: tracing::Span::current(),
+            finished: false,
+        }
+    }
+
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.span.record("job.cancelled", &true);
+            self.span
+                .in_scope(|| tracing::info!("job cancelled before it finished"));
+        }
+    }
+}
+
+/// Jobs are cancelled by dropping them, which kills the compiler and stops
+/// the guest. This happens when the client disconnects.
+async fn cancellable<T>(job: impl std::future::Future<Output = T>) -> T {
+    let guard = CancelGuard::new();
+    let output = job.await;
+    guard.finish();
+    output
+}
+
 #[instrument(skip_all, name = "Run playground code", fields(
-    service.name = "typerust"
+    service.name = "typerust",
+    build.cache = tracing::field::Empty,
+    job.cancelled = tracing::field::Empty
+))]
+pub async fn run(
+    code: String,
+    options: BuildOptions,
+    requested_limits: RequestedLimits,
+    client: ClientId,
+    state: Arc<State>,
+) -> Result<HandlerResponse> {
+    cancellable(run_and_report(
+        code,
+        options,
+        requested_limits,
+        client,
+        state,
+        None,
+        None,
+    ))
+    .await
+}
+
+/// Runs the code like [`run`] while reporting progress and output to `events`.
+/// The final response is returned rather than sent.
+#[instrument(skip_all, name = "Run playground code with streaming", fields(
+    service.name = "typerust",
+    build.cache = tracing::field::Empty,
+    job.cancelled = tracing::field::Empty
 ))]
-pub async fn run(code: String, state: Arc<State>) -> Result<HandlerResponse> {
+pub async fn run_streaming(
+    code: String,
+    options: BuildOptions,
+    requested_limits: RequestedLimits,
+    client: ClientId,
+    state: Arc<State>,
+    events: RunEvents,
+) -> Result<HandlerResponse> {
+    cancellable(run_and_report(
+        code,
+        options,
+        requested_limits,
+        client,
+        state,
+        Some(events),
+        None,
+    ))
+    .await
+}
+
+/// Runs the code like [`run_streaming`] with `stdin` connected to the guest.
+/// Time spent waiting for input doesn't count against the timeout.
+#[instrument(skip_all, name = "Run playground code interactively", fields(
+    service.name = "typerust",
+    build.cache = tracing::field::Empty,
+    job.cancelled = tracing::field::Empty
+))]
+pub async fn run_interactive(
+    code: String,
+    options: BuildOptions,
+    requested_limits: RequestedLimits,
+    client: ClientId,
+    state: Arc<State>,
+    events: RunEvents,
+    stdin: mpsc::Receiver<Bytes>,
+) -> Result<HandlerResponse> {
+    cancellable(run_and_report(
+        code,
+        options,
+        requested_limits,
+        client,
+        state,
+        Some(events),
+        Some(stdin),
+    ))
+    .await
+}
+
+async fn run_and_report(
+    code: String,
+    options: BuildOptions,
+    requested_limits: RequestedLimits,
+    client: ClientId,
+    state: Arc<State>,
+    events: Option<RunEvents>,
+    stdin: Option<mpsc::Receiver<Bytes>>,
+) -> Result<HandlerResponse> {
+    let limits = match state.limits.resolve(&requested_limits, state.fuel) {
+        Ok(limits) => limits,
+        Err(message) => return Ok(HandlerResponse::Error(message)),
+    };
+    let target = match Target::for_run(options.crate_type) {
+        Some(target) => target,
+        None => {
+            return Ok(HandlerResponse::Error(
+                "proc-macro crates can only be built, not run".into(),
+            ))
+        }
+    };
+    let on_queued = |position| emit(events.as_ref(), RunEvent::Queued { position });
+    let permit = state.scheduler.compile_slot(&client, on_queued).await?;
+    emit(events.as_ref(), RunEvent::Compiling);
     let compiler = Compiler::new().await?;
-    let result = compiler.compile(code).await?;
+    let (result, cache) = compiler
+        .compile_cached(code, target, state.build_cache.as_ref())
+        .await?;
+    drop(permit);
     match result {
         BuildResult::Success {
             elapsed,
@@ -145,54 +585,179 @@ pub async fn run(code: String, state: Arc<State>) -> Result<HandlerResponse> {
         } => {
             tracing::info!("successfully compiled playground code");
             let elapsed = elapsed.as_secs_f32();
-            let output = execute_wasm(state.engine.clone(), executable).await?;
+            emit(events.as_ref(), RunEvent::Compiled { elapsed, cache });
+            let loaded = load_module(&state.engine, executable, state.build_cache.as_ref()).await?;
+            let module = ModuleInfo {
+                elapsed: loaded.elapsed.as_secs_f32(),
+                cache: loaded.cache,
+            };
+            let on_queued = |position| emit(events.as_ref(), RunEvent::Queued { position });
+            let _permit = state.scheduler.execute_slot(&client, on_queued).await?;
+            emit(events.as_ref(), RunEvent::Running);
+            let io = GuestIo {
+                args: target.run_args(),
+                sink: events.map(output_sink),
+                stdin,
+            };
+            let execution = execute_wasm(state.engine.clone(), loaded.module, limits, io).await?;
+            if execution.exit_code != 0 {
+                return Ok(runtime_failure(execution));
+            }
             let success = Success {
                 elapsed,
-                output: Some(output),
+                output: Some(execution.output),
+                stderr: Some(execution.stderr),
+                truncated: execution.truncated,
+                cache,
+                module: Some(module),
+                usage: Some(execution.usage),
             };
             Ok(HandlerResponse::Success(success))
         }
         BuildResult::Failure(output) => {
             tracing::error!("failed to build playground code");
-            Ok(HandlerResponse::Error(output))
+            Ok(HandlerResponse::BuildFailed { output, cache })
         }
     }
 }
 
+/// Shows what the program printed before it failed, stderr last since that
+/// is where panic messages go.
+fn runtime_failure(execution: Execution) -> HandlerResponse {
+    let exit_code = execution.exit_code;
+    tracing::info!(exit_code, "playground code failed at runtime");
+    let mut output = execution.output;
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    output.push_str(&execution.stderr);
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    output.push_str(&format!(
+        "RUNTIME ERROR: Your code exited with code {exit_code}"
+    ));
+    HandlerResponse::RuntimeFailed { output, exit_code }
+}
+
 #[instrument(skip_all, name = "Build playground code", fields(
-    service.name = "typerust"
+    service.name = "typerust",
+    build.cache = tracing::field::Empty,
+    job.cancelled = tracing::field::Empty
+))]
+pub async fn build(
+    code: String,
+    options: BuildOptions,
+    client: ClientId,
+    state: Arc<State>,
+) -> Result<HandlerResponse> {
+    cancellable(build_and_report(code, options, client, state, None)).await
+}
+
+/// Builds the code like [`build`] while reporting progress to `events`.
+#[instrument(skip_all, name = "Build playground code with streaming", fields(
+    service.name = "typerust",
+    build.cache = tracing::field::Empty,
+    job.cancelled = tracing::field::Empty
 ))]
-pub async fn build(code: String) -> Result<HandlerResponse> {
+pub async fn build_streaming(
+    code: String,
+    options: BuildOptions,
+    client: ClientId,
+    state: Arc<State>,
+    events: RunEvents,
+) -> Result<HandlerResponse> {
+    cancellable(build_and_report(code, options, client, state, Some(events))).await
+}
+
+async fn build_and_report(
+    code: String,
+    options: BuildOptions,
+    client: ClientId,
+    state: Arc<State>,
+    events: Option<RunEvents>,
+) -> Result<HandlerResponse> {
+    let on_queued = |position| emit(events.as_ref(), RunEvent::Queued { position });
+    let permit = state.scheduler.compile_slot(&client, on_queued).await?;
+    emit(events.as_ref(), RunEvent::Compiling);
     let sandbox = Compiler::new().await?;
-    let build_result = sandbox.compile(code).await?;
+    let target = Target::for_build(options.crate_type);
+    let (build_result, cache) = sandbox
+        .compile_cached(code, target, state.build_cache.as_ref())
+        .await?;
+    drop(permit);
     match build_result {
         BuildResult::Success { elapsed, .. } => {
             tracing::info!("successfully compiled playground code");
             let elapsed = elapsed.as_secs_f32();
+            emit(events.as_ref(), RunEvent::Compiled { elapsed, cache });
             let success = Success {
                 elapsed,
                 output: None,
+                stderr: None,
+                truncated: false,
+                cache,
+                module: None,
+                usage: None,
             };
             Ok(HandlerResponse::Success(success))
         }
         BuildResult::Failure(output) => {
             tracing::error!("failed to build playground code");
-            Ok(HandlerResponse::Error(output))
+            Ok(HandlerResponse::BuildFailed { output, cache })
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::cache::CacheConfig;
+    use crate::jobs::{JobStatus, JobTable, JobsConfig};
+    use crate::limits::LimitsConfig;
+    use crate::rate_limit::{RateLimitConfig, RateLimiter};
+    use crate::scheduler::{Scheduler, SchedulerConfig};
+    use crate::snippets::{Snippet, SnippetStore, SnippetsConfig, StoredResult};
+    use crate::wasm::{
+        EngineConfig, ExecutionLimits, TRAP_EXIT_CODE, WASM_INSTANCE_MEMORY_LIMIT,
+        WASM_MINIMUM_MEMORY_SIZE,
+    };
     use crate::{create_interruptable_engine, error::SandboxError};
     use once_cell::sync::Lazy;
+    use std::net::IpAddr;
 
-    static STATE: Lazy<Arc<State>> = Lazy::new(|| {
+    static STATE: Lazy<Arc<State>> = Lazy::new(|| test_state(EngineConfig::default(), None));
+
+    pub(crate) fn test_state(
+        engine_config: EngineConfig,
+        build_cache: Option<BuildCache>,
+    ) -> Arc<State> {
+        let limits = LimitsConfig::default();
         Arc::new(State {
-            engine: create_interruptable_engine(),
+            engine: create_interruptable_engine(&engine_config, limits.max_memory),
+            limits,
+            fuel: engine_config.fuel,
+            build_cache,
+            scheduler: Scheduler::new(&SchedulerConfig::default()),
+            rate_limiter: RateLimiter::new(RateLimitConfig::default()),
+            jobs: Arc::new(JobTable::new(&JobsConfig::default())),
+            snippets: SnippetStore::new(SnippetsConfig {
+                dir: tempfile::tempdir().unwrap().into_path(),
+            })
+            .unwrap(),
         })
-    });
+    }
+
+    fn client() -> ClientId {
+        ClientId::from(IpAddr::from([127, 0, 0, 1]))
+    }
+
+    fn default_limits() -> ExecutionLimits {
+        STATE
+            .limits
+            .resolve(&RequestedLimits::default(), None)
+            .unwrap()
+    }
 
     #[tokio::test]
     async fn kill_on_oom() -> anyhow::Result<()> {
@@ -206,11 +771,19 @@ fn main() {
 }
         "#;
 
-        let result = sandbox.compile(code.into()).await?;
+        let result = sandbox.compile(code.into(), Target::Executable).await?;
 
         assert!(matches!(result, BuildResult::Success { .. }));
         if let BuildResult::Success { executable, .. } = result {
-            let result = execute_wasm(STATE.engine.clone(), executable).await.err();
+            let module = load_module(&STATE.engine, executable, None).await?.module;
+            let result = execute_wasm(
+                STATE.engine.clone(),
+                module,
+                default_limits(),
+                GuestIo::default(),
+            )
+            .await
+            .err();
             let error = result.map(|err| err.downcast::<SandboxError>());
             assert!(matches!(error, Some(Ok(SandboxError::OOM))));
         }
@@ -230,13 +803,21 @@ fn main() {
 }
         "#;
 
-        let result = sandbox.compile(code.into()).await?;
+        let result = sandbox.compile(code.into(), Target::Executable).await?;
 
         assert!(matches!(result, BuildResult::Success { .. }));
 
         if let BuildResult::Success { executable, .. } = result {
-            let result = execute_wasm(STATE.engine.clone(), executable).await.err();
-            assert!(matches!(result, None));
+            let module = load_module(&STATE.engine, executable, None).await?.module;
+            let result = execute_wasm(
+                STATE.engine.clone(),
+                module,
+                default_limits(),
+                GuestIo::default(),
+            )
+            .await
+            .err();
+            assert!(result.is_none());
         }
 
         Ok(())
@@ -251,16 +832,837 @@ fn main() {
 }
         "#;
 
-        let result = sandbox.compile(code.into()).await?;
+        let result = sandbox.compile(code.into(), Target::Executable).await?;
+
+        assert!(matches!(result, BuildResult::Success { .. }));
+
+        if let BuildResult::Success { executable, .. } = result {
+            let module = load_module(&STATE.engine, executable, None).await?.module;
+            let result = execute_wasm(
+                STATE.engine.clone(),
+                module,
+                default_limits(),
+                GuestIo::default(),
+            )
+            .await
+            .err();
+            let error = result.map(|err| err.downcast::<SandboxError>());
+            assert!(matches!(error, Some(Ok(SandboxError::Timeout))));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn yield_to_other_tasks_while_running() -> anyhow::Result<()> {
+        let sandbox = Compiler::new().await?;
+        let code = r#"
+fn main() {
+    loop {}
+}
+        "#;
+
+        let result = sandbox.compile(code.into(), Target::Executable).await?;
+
+        if let BuildResult::Success { executable, .. } = result {
+            let module = load_module(&STATE.engine, executable, None).await?.module;
+            let limits = ExecutionLimits {
+                timeout: Duration::from_secs(1),
+                ..default_limits()
+            };
+            // Tests run on a single-threaded runtime, so the timer only fires
+            // in time if the guest gives up the thread.
+            let start = Instant::now();
+            let (result, slept) = tokio::join!(
+                execute_wasm(STATE.engine.clone(), module, limits, GuestIo::default()),
+                async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    start.elapsed()
+                }
+            );
+            assert!(result.is_err());
+            assert!(slept < Duration::from_millis(500));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_library_without_main() -> anyhow::Result<()> {
+        let code = r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+        "#;
+
+        let options = BuildOptions {
+            crate_type: CrateType::Lib,
+        };
+        let response = build(code.into(), options, client(), STATE.clone()).await?;
+
+        assert!(matches!(response, HandlerResponse::Success(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_library_tests() -> anyhow::Result<()> {
+        let code = r#"
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[test]
+fn adds() {
+    assert_eq!(add(2, 2), 4);
+}
+        "#;
+
+        let options = BuildOptions {
+            crate_type: CrateType::Lib,
+        };
+        let response = run(
+            code.into(),
+            options,
+            RequestedLimits::default(),
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { output: Some(output), .. })
+                if output.contains("test result: ok. 1 passed")
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_failing_library_tests() -> anyhow::Result<()> {
+        let code = r#"
+#[test]
+fn fails() {
+    assert_eq!(1 + 1, 3, "math is broken");
+}
+        "#;
+
+        let options = BuildOptions {
+            crate_type: CrateType::Lib,
+        };
+        let response = run(
+            code.into(),
+            options,
+            RequestedLimits::default(),
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+
+        assert!(
+            matches!(
+                &response,
+                HandlerResponse::RuntimeFailed { output, exit_code: TRAP_EXIT_CODE }
+                    if output.contains("test fails ...") && output.contains("math is broken")
+            ),
+            "{}",
+            serde_json::to_string(&response)?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_panics_and_exit_codes() -> anyhow::Result<()> {
+        let run_code = |code: &str| {
+            run(
+                code.into(),
+                BuildOptions::default(),
+                RequestedLimits::default(),
+                client(),
+                STATE.clone(),
+            )
+        };
+
+        let response = run_code(
+            r#"
+fn main() {
+    println!("before");
+    panic!("boom");
+}
+            "#,
+        )
+        .await?;
+        match &response {
+            HandlerResponse::RuntimeFailed { output, exit_code } => {
+                assert_eq!(*exit_code, TRAP_EXIT_CODE);
+                assert!(output.starts_with("before\n"), "{output}");
+                assert!(
+                    output.contains("panicked") && output.contains("boom"),
+                    "{output}"
+                );
+                assert!(output.ends_with(&format!("exited with code {TRAP_EXIT_CODE}")));
+            }
+            _ => panic!("expected a runtime failure, got {:?}", response.text()),
+        }
+        let json = serde_json::to_value(&response)?;
+        assert_eq!(json["type"], "Error");
+        assert_eq!(json["exit_code"], TRAP_EXIT_CODE);
+        let parsed: HandlerResponse = serde_json::from_value(json)?;
+        assert!(matches!(parsed, HandlerResponse::RuntimeFailed { .. }));
+
+        let response = run_code("fn main() { std::process::exit(3); }").await?;
+        assert!(matches!(
+            response,
+            HandlerResponse::RuntimeFailed { exit_code: 3, .. }
+        ));
+
+        let response =
+            run_code("fn main() { eprintln!(\"warning\"); std::process::exit(0); }").await?;
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { stderr: Some(stderr), .. }) if stderr == "warning\n"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_proc_macro_but_refuse_to_run() -> anyhow::Result<()> {
+        let code = r#"
+extern crate proc_macro;
+use proc_macro::TokenStream;
+
+#[proc_macro]
+pub fn identity(input: TokenStream) -> TokenStream {
+    input
+}
+        "#;
+
+        let options = BuildOptions {
+            crate_type: CrateType::ProcMacro,
+        };
+        let response = build(code.into(), options, client(), STATE.clone()).await?;
+        assert!(matches!(response, HandlerResponse::Success(_)));
+
+        let options = BuildOptions {
+            crate_type: CrateType::ProcMacro,
+        };
+        let response = run(
+            code.into(),
+            options,
+            RequestedLimits::default(),
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+        assert!(matches!(response, HandlerResponse::Error(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuse_cached_build() -> anyhow::Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        let config = CacheConfig {
+            enabled: true,
+            dir: cache_dir.path().to_path_buf(),
+            max_size: bytesize::MB * 64,
+            max_age: 60,
+        };
+        let state = test_state(
+            EngineConfig::default(),
+            Some(BuildCache::new(config).await?),
+        );
+        let code = r#"
+fn main() {
+    println!("cached");
+}
+        "#;
+
+        for expected in [CacheStatus::Miss, CacheStatus::Hit] {
+            let response = run(
+                code.into(),
+                BuildOptions::default(),
+                RequestedLimits::default(),
+                client(),
+                state.clone(),
+            )
+            .await?;
+            assert!(matches!(
+                response,
+                HandlerResponse::Success(Success {
+                    output: Some(output),
+                    cache,
+                    module: Some(ModuleInfo { cache: module_cache, .. }),
+                    ..
+                }) if output == "cached\n" && cache == expected && module_cache == expected
+            ));
+        }
+
+        let code = "fn main() { let x: u32 = \"oops\"; }";
+        for expected in [CacheStatus::Miss, CacheStatus::Hit] {
+            let response = build(
+                code.into(),
+                BuildOptions::default(),
+                client(),
+                state.clone(),
+            )
+            .await?;
+            assert!(matches!(
+                &response,
+                HandlerResponse::BuildFailed { cache, .. } if *cache == expected
+            ));
+            let json = serde_json::to_value(&response)?;
+            assert_eq!(json["type"], "Error");
+            assert!(json["data"].is_string());
+            assert_eq!(json["cache"], serde_json::to_value(expected)?);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn busy_when_instance_pool_is_exhausted() -> anyhow::Result<()> {
+        let config = EngineConfig {
+            max_instances: 1,
+            ..EngineConfig::default()
+        };
+        let engine = create_interruptable_engine(&config, WASM_INSTANCE_MEMORY_LIMIT);
+
+        let sandbox = Compiler::new().await?;
+        let code = r#"
+fn main() {
+    std::thread::sleep(std::time::Duration::from_secs(1));
+}
+        "#;
+
+        let result = sandbox.compile(code.into(), Target::Executable).await?;
 
         assert!(matches!(result, BuildResult::Success { .. }));
 
         if let BuildResult::Success { executable, .. } = result {
-            let result = execute_wasm(STATE.engine.clone(), executable).await.err();
+            let module = load_module(&engine, executable, None).await?.module;
+            let (first, second) = tokio::join!(
+                execute_wasm(
+                    engine.clone(),
+                    module.clone(),
+                    default_limits(),
+                    GuestIo::default()
+                ),
+                execute_wasm(engine.clone(), module, default_limits(), GuestIo::default())
+            );
+            let errors: Vec<_> = [first.err(), second.err()]
+                .into_iter()
+                .flatten()
+                .map(|err| err.downcast::<SandboxError>())
+                .collect();
+            assert!(matches!(errors[..], [Ok(SandboxError::Busy)]));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn free_sandbox_when_run_is_cancelled() -> anyhow::Result<()> {
+        let config = EngineConfig {
+            max_instances: 1,
+            ..EngineConfig::default()
+        };
+        let engine = create_interruptable_engine(&config, WASM_INSTANCE_MEMORY_LIMIT);
+
+        let sandbox = Compiler::new().await?;
+        let code = r#"
+fn main() {
+    loop {}
+}
+        "#;
+
+        let result = sandbox.compile(code.into(), Target::Executable).await?;
+
+        if let BuildResult::Success { executable, .. } = result {
+            let module = load_module(&engine, executable, None).await?.module;
+            let cancelled = tokio::time::timeout(
+                Duration::from_millis(200),
+                execute_wasm(
+                    engine.clone(),
+                    module.clone(),
+                    default_limits(),
+                    GuestIo::default(),
+                ),
+            )
+            .await;
+            assert!(cancelled.is_err());
+
+            let limits = ExecutionLimits {
+                timeout: Duration::from_millis(200),
+                ..default_limits()
+            };
+            let result = execute_wasm(engine, module, limits, GuestIo::default())
+                .await
+                .err();
             let error = result.map(|err| err.downcast::<SandboxError>());
             assert!(matches!(error, Some(Ok(SandboxError::Timeout))));
         }
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn poll_and_cancel_background_jobs() -> anyhow::Result<()> {
+        let submit = |code: &'static str| {
+            STATE.jobs.submit(move |events| async move {
+                let result = run_streaming(
+                    code.into(),
+                    BuildOptions::default(),
+                    RequestedLimits::default(),
+                    client(),
+                    STATE.clone(),
+                    events,
+                )
+                .await;
+                result.unwrap_or_else(|e| HandlerResponse::Error(e.to_string()))
+            })
+        };
+
+        let id = submit("fn main() { println!(\"polled\"); }")?;
+        let report = loop {
+            let report = STATE.jobs.get(&id).expect("job is missing");
+            if report.status == JobStatus::Done {
+                break report;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert!(matches!(
+            report.result,
+            Some(HandlerResponse::Success(Success { output: Some(output), .. })) if output == "polled\n"
+        ));
+
+        let id = submit("fn main() { loop {} }")?;
+        assert!(STATE.jobs.cancel(&id));
+        assert!(STATE.jobs.get(&id).is_none());
+        assert!(!STATE.jobs.cancel(&id));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_runs_with_stored_results() -> anyhow::Result<()> {
+        let snippet = Snippet {
+            code: "fn main() { println!(\"stored\"); }".into(),
+            options: BuildOptions::default(),
+            parent: None,
+            forked_from: None,
+        };
+        let id = STATE.snippets.put(&snippet).await?;
+        assert!(STATE.snippets.get_result(&id).await?.is_none());
+
+        let run_snippet = || {
+            run(
+                snippet.code.clone(),
+                snippet.options.clone(),
+                RequestedLimits::default(),
+                client(),
+                STATE.clone(),
+            )
+        };
+        let result = StoredResult {
+            toolchain: toolchain_version().await,
+            response: run_snippet().await?,
+        };
+        assert!(result.toolchain.starts_with("rustc "));
+        assert!(STATE.snippets.put_result(&id, &result).await?);
+        let other = StoredResult {
+            toolchain: result.toolchain.clone(),
+            response: HandlerResponse::Error("overwritten".into()),
+        };
+        assert!(!STATE.snippets.put_result(&id, &other).await?);
+
+        let stored = STATE.snippets.get_result(&id).await?.unwrap();
+        assert!(matches!(
+            &stored.response,
+            HandlerResponse::Success(Success { output: Some(output), .. }) if output == "stored\n"
+        ));
+        assert!(stored.response.same_output(&run_snippet().await?));
+        assert!(!stored
+            .response
+            .same_output(&HandlerResponse::Error("different".into())));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn meter_fuel_deterministically() -> anyhow::Result<()> {
+        let config = EngineConfig {
+            fuel: Some(10_000_000),
+            ..EngineConfig::default()
+        };
+        let state = test_state(config, None);
+        let code = r#"
+fn main() {
+    let sum: u64 = (0..1000).sum();
+    println!("{sum}");
+}
+        "#;
+
+        let mut consumed = vec![];
+        for _ in 0..2 {
+            let response = run(
+                code.into(),
+                BuildOptions::default(),
+                RequestedLimits::default(),
+                client(),
+                state.clone(),
+            )
+            .await?;
+            if let HandlerResponse::Success(success) = response {
+                consumed.push(success.usage.and_then(|usage| usage.fuel_consumed));
+            }
+        }
+        assert!(
+            matches!(consumed[..], [Some(first), Some(second)] if first > 0 && first == second)
+        );
+
+        let code = r#"
+fn main() {
+    loop {}
+}
+        "#;
+        let result = run(
+            code.into(),
+            BuildOptions::default(),
+            RequestedLimits::default(),
+            client(),
+            state,
+        )
+        .await
+        .err();
+        assert!(matches!(result, Some(SandboxError::FuelExhausted)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_resource_usage() -> anyhow::Result<()> {
+        let code = r#"
+fn main() {
+    let bytes = vec![1u8; 10_000_000];
+    println!("{}", bytes.len());
+}
+        "#;
+
+        let response = run(
+            code.into(),
+            BuildOptions::default(),
+            RequestedLimits::default(),
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { usage: Some(usage), .. })
+                if usage.peak_memory > 10_000_000
+                    && usage.memory_grow_events > 0
+                    && usage.stdout_bytes == 9
+                    && usage.fuel_consumed.is_none()
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_limits_within_bounds() {
+        let config = LimitsConfig::default();
+
+        let limits = config.resolve(&RequestedLimits::default(), None).unwrap();
+        assert_eq!(limits.memory, config.default_memory);
+        assert_eq!(limits.timeout.as_millis() as u64, config.default_timeout_ms);
+        assert_eq!(limits.output, config.default_output);
+
+        let at_max = RequestedLimits {
+            memory: Some(config.max_memory),
+            timeout_ms: Some(config.max_timeout_ms),
+            output: Some(config.max_output),
+        };
+        let limits = config.resolve(&at_max, None).unwrap();
+        assert_eq!(limits.memory, config.max_memory);
+        assert_eq!(limits.timeout.as_millis() as u64, config.max_timeout_ms);
+        assert_eq!(limits.output, config.max_output);
+
+        let at_min = RequestedLimits {
+            memory: Some(WASM_MINIMUM_MEMORY_SIZE),
+            timeout_ms: Some(1),
+            output: Some(1),
+        };
+        assert!(config.resolve(&at_min, None).is_ok());
+    }
+
+    #[test]
+    fn reject_limits_out_of_bounds() {
+        let config = LimitsConfig::default();
+        let out_of_bounds = [
+            RequestedLimits {
+                memory: Some(config.max_memory + 1),
+                ..RequestedLimits::default()
+            },
+            RequestedLimits {
+                memory: Some(WASM_MINIMUM_MEMORY_SIZE - 1),
+                ..RequestedLimits::default()
+            },
+            RequestedLimits {
+                timeout_ms: Some(config.max_timeout_ms + 1),
+                ..RequestedLimits::default()
+            },
+            RequestedLimits {
+                timeout_ms: Some(0),
+                ..RequestedLimits::default()
+            },
+            RequestedLimits {
+                output: Some(config.max_output + 1),
+                ..RequestedLimits::default()
+            },
+            RequestedLimits {
+                output: Some(0),
+                ..RequestedLimits::default()
+            },
+        ];
+
+        for requested in out_of_bounds {
+            assert!(config.resolve(&requested, None).is_err(), "{requested:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_requested_limits() -> anyhow::Result<()> {
+        let code = r#"
+fn main() {
+    let bytes = vec![1u8; 10_000_000];
+    println!("{}", bytes.len());
+}
+        "#;
+        let requested = RequestedLimits {
+            memory: Some(bytesize::MB * 5),
+            ..RequestedLimits::default()
+        };
+        let result = run(
+            code.into(),
+            BuildOptions::default(),
+            requested,
+            client(),
+            STATE.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(SandboxError::OOM)));
+
+        let code = r#"
+fn main() {
+    loop {}
+}
+        "#;
+        let requested = RequestedLimits {
+            timeout_ms: Some(200),
+            ..RequestedLimits::default()
+        };
+        let start = Instant::now();
+        let result = run(
+            code.into(),
+            BuildOptions::default(),
+            requested,
+            client(),
+            STATE.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(SandboxError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let code = r#"
+fn main() {
+    println!("{}", "a".repeat(100));
+}
+        "#;
+        let requested = RequestedLimits {
+            output: Some(10),
+            ..RequestedLimits::default()
+        };
+        let response = run(
+            code.into(),
+            BuildOptions::default(),
+            requested,
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { output: Some(output), .. }) if output == "a".repeat(10)
+        ));
+
+        let requested = RequestedLimits {
+            output: Some(STATE.limits.max_output + 1),
+            ..RequestedLimits::default()
+        };
+        let response = run(
+            code.into(),
+            BuildOptions::default(),
+            requested,
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+        assert!(matches!(response, HandlerResponse::Error(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_endless_output_at_limit() -> anyhow::Result<()> {
+        let code = r#"
+fn main() {
+    loop {
+        println!("spam");
+    }
+}
+        "#;
+        let requested = RequestedLimits {
+            output: Some(1000),
+            ..RequestedLimits::default()
+        };
+        let start = Instant::now();
+        let response = run(
+            code.into(),
+            BuildOptions::default(),
+            requested,
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { output: Some(output), truncated: true, .. })
+                if output.len() == 1000 && output.starts_with("spam\n")
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replace_invalid_utf8_output() -> anyhow::Result<()> {
+        let code = r#"
+use std::io::Write;
+
+fn main() {
+    std::io::stdout().write_all(&[b'o', b'k', 0xff, b'\n']).unwrap();
+}
+        "#;
+
+        let response = run(
+            code.into(),
+            BuildOptions::default(),
+            RequestedLimits::default(),
+            client(),
+            STATE.clone(),
+        )
+        .await?;
+
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { output: Some(output), truncated: false, .. })
+                if output == "ok\u{FFFD}\n"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_run_events() -> anyhow::Result<()> {
+        let code = r#"
+fn main() {
+    println!("out");
+    eprintln!("err");
+}
+        "#;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let response = run_streaming(
+            code.into(),
+            BuildOptions::default(),
+            RequestedLimits::default(),
+            client(),
+            STATE.clone(),
+            sender,
+        )
+        .await?;
+        assert!(matches!(response, HandlerResponse::Success(_)));
+
+        let mut events = vec![];
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        assert!(matches!(
+            &events[..],
+            [
+                RunEvent::Compiling,
+                RunEvent::Compiled { .. },
+                RunEvent::Running,
+                RunEvent::Stdout(stdout),
+                RunEvent::Stderr(stderr),
+            ] if stdout == "out\n" && stderr == "err\n"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feed_interactive_stdin() -> anyhow::Result<()> {
+        let code = r#"
+use std::io::BufRead;
+
+fn main() {
+    for line in std::io::stdin().lock().lines() {
+        println!("echo: {}", line.unwrap());
+    }
+}
+        "#;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let (stdin, stdin_receiver) = mpsc::channel(1);
+        // Waiting for input must not count against the timeout.
+        let requested = RequestedLimits {
+            timeout_ms: Some(300),
+            ..RequestedLimits::default()
+        };
+        let run = tokio::spawn(run_interactive(
+            code.into(),
+            BuildOptions::default(),
+            requested,
+            client(),
+            STATE.clone(),
+            sender,
+            stdin_receiver,
+        ));
+
+        while !matches!(receiver.recv().await, Some(RunEvent::Running) | None) {}
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        stdin.send(Bytes::from("first\n")).await?;
+        assert!(matches!(
+            receiver.recv().await,
+            Some(RunEvent::Stdout(stdout)) if stdout == "echo: first\n"
+        ));
+        stdin.send(Bytes::from("second\n")).await?;
+        drop(stdin);
+
+        let response = run.await??;
+        assert!(matches!(
+            response,
+            HandlerResponse::Success(Success { output: Some(output), .. })
+                if output == "echo: first\necho: second\n"
+        ));
+
+        Ok(())
+    }
 }
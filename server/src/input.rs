@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{Duration, Instant};
+use wasmtime_wasi::{InputStream, Pollable, StdinStream, StreamError, StreamResult};
+
+/// Raised inside the guest when nobody typed anything for too long.
+#[derive(Error, Debug)]
+#[error("idle timeout reached")]
+pub struct IdleTimeoutReached;
+
+/// Total time the guest spent waiting for input, shared with the epoch
+/// deadline callback so waiting doesn't count against the run's time budget.
+#[derive(Clone, Default)]
+pub struct IdleClock {
+    micros: Arc<AtomicU64>,
+}
+
+impl IdleClock {
+    fn add(&self, idle: Duration) {
+        self.micros
+            .fetch_add(idle.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_micros(self.micros.load(Ordering::Relaxed))
+    }
+}
+
+struct Inner {
+    receiver: mpsc::Receiver<Bytes>,
+    pending: Bytes,
+    closed: bool,
+    idle_expired: bool,
+}
+
+/// Guest stdin fed by a client while the program runs. Dropping the sending
+/// side of the channel closes stdin.
+#[derive(Clone)]
+pub struct InteractiveStdin {
+    inner: Arc<Mutex<Inner>>,
+    idle: IdleClock,
+    idle_timeout: Duration,
+}
+
+impl InteractiveStdin {
+    pub fn new(receiver: mpsc::Receiver<Bytes>, idle: IdleClock, idle_timeout: Duration) -> Self {
+        let inner = Inner {
+            receiver,
+            pending: Bytes::new(),
+            closed: false,
+            idle_expired: false,
+        };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            idle,
+            idle_timeout,
+        }
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl InputStream for InteractiveStdin {
+    fn read(&mut self, size: usize) -> StreamResult<Bytes> {
+        // Readiness is only awaited by the guest thread in between reads.
+        let mut inner = self
+            .inner
+            .try_lock()
+            .map_err(|_| StreamError::trap("concurrent stdin access"))?;
+
+        if inner.idle_expired {
+            return Err(StreamError::Trap(IdleTimeoutReached.into()));
+        }
+        if !inner.pending.is_empty() {
+            let size = size.min(inner.pending.len());
+            return Ok(inner.pending.split_to(size));
+        }
+        if inner.closed {
+            return Err(StreamError::Closed);
+        }
+        Ok(Bytes::new())
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for InteractiveStdin {
+    async fn ready(&mut self) {
+        let mut inner = self.inner.lock().await;
+        let start = Instant::now();
+
+        while inner.pending.is_empty() && !inner.closed && !inner.idle_expired {
+            let left = self.idle_timeout.saturating_sub(start.elapsed());
+            match tokio::time::timeout(left, inner.receiver.recv()).await {
+                Ok(Some(bytes)) => inner.pending = bytes,
+                Ok(None) => inner.closed = true,
+                Err(_) => inner.idle_expired = true,
+            }
+        }
+
+        self.idle.add(start.elapsed());
+    }
+}
+
+impl StdinStream for InteractiveStdin {
+    fn stream(&self) -> Box<dyn InputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
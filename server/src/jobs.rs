@@ -0,0 +1,216 @@
+use crate::handler::{HandlerResponse, RunEvent, RunEvents};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time::{Duration, Instant};
+use tracing::Instrument;
+
+fn default_ttl() -> u64 {
+    10 * 60
+}
+
+fn default_max_jobs() -> usize {
+    1_000
+}
+
+/// Read from `JOBS_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct JobsConfig {
+    /// Seconds a finished job's result is kept around for polling.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    /// Jobs kept at the same time, finished or not.
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: usize,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            ttl: default_ttl(),
+            max_jobs: default_max_jobs(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    #[default]
+    Run,
+    Build,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct JobOptions {
+    #[serde(default)]
+    pub kind: JobKind,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Compiling,
+    Running,
+    Done,
+}
+
+#[derive(Error, Debug)]
+#[error("too many jobs")]
+pub struct TooManyJobs;
+
+struct Job {
+    status: JobStatus,
+    position: Option<usize>,
+    result: Option<HandlerResponse>,
+    finished: Option<Instant>,
+    task: Option<AbortHandle>,
+}
+
+/// What a client sees when polling a job.
+#[derive(Serialize, Clone)]
+pub struct JobReport {
+    pub id: String,
+    pub status: JobStatus,
+    /// Position in the queue while waiting for a slot, starting at 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<HandlerResponse>,
+}
+
+/// Jobs submitted to run in the background, kept in memory until some time
+/// after they finish.
+pub struct JobTable {
+    jobs: Mutex<HashMap<String, Job>>,
+    ttl: Duration,
+    max_jobs: usize,
+}
+
+impl JobTable {
+    pub fn new(config: &JobsConfig) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            ttl: Duration::from_secs(config.ttl),
+            max_jobs: config.max_jobs,
+        }
+    }
+
+    /// Starts `job` in the background and returns its ID. The job reports its
+    /// progress to the events it is given and returns the final response.
+    pub fn submit<F, Fut>(self: &Arc<Self>, job: F) -> Result<String, TooManyJobs>
+    where
+        F: FnOnce(RunEvents) -> Fut,
+        Fut: Future<Output = HandlerResponse> + Send + 'static,
+    {
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            self.purge(&mut jobs);
+            if jobs.len() >= self.max_jobs {
+                return Err(TooManyJobs);
+            }
+            let job = Job {
+                status: JobStatus::Queued,
+                position: None,
+                result: None,
+                finished: None,
+                task: None,
+            };
+            jobs.insert(id.clone(), job);
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let run = job(sender);
+        let table = self.clone();
+        let job_id = id.clone();
+        let task = async move {
+            tokio::pin!(run);
+            loop {
+                tokio::select! {
+                    response = &mut run => {
+                        table.finish(&job_id, response);
+                        break;
+                    }
+                    Some(event) = receiver.recv() => table.update(&job_id, event),
+                }
+            }
+        };
+        let handle = tokio::spawn(task.in_current_span());
+
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.task = Some(handle.abort_handle());
+        }
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobReport> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.purge(&mut jobs);
+        jobs.get(id).map(|job| JobReport {
+            id: id.to_owned(),
+            status: job.status,
+            position: job.position,
+            result: job.result.clone(),
+        })
+    }
+
+    /// Stops the job if it is still going and forgets about it.
+    pub fn cancel(&self, id: &str) -> bool {
+        let job = self.jobs.lock().unwrap().remove(id);
+        match job {
+            Some(job) => {
+                if let Some(task) = job.task {
+                    task.abort();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn update(&self, id: &str, event: RunEvent) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        match event {
+            RunEvent::Queued { position } => {
+                job.status = JobStatus::Queued;
+                job.position = Some(position);
+            }
+            RunEvent::Compiling => {
+                job.status = JobStatus::Compiling;
+                job.position = None;
+            }
+            RunEvent::Running => {
+                job.status = JobStatus::Running;
+                job.position = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(&self, id: &str, response: HandlerResponse) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(id) {
+            job.status = JobStatus::Done;
+            job.position = None;
+            job.result = Some(response);
+            job.finished = Some(Instant::now());
+            job.task = None;
+        }
+    }
+
+    fn purge(&self, jobs: &mut HashMap<String, Job>) {
+        jobs.retain(|_, job| match job.finished {
+            Some(finished) => finished.elapsed() < self.ttl,
+            None => true,
+        });
+    }
+}
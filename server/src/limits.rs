@@ -0,0 +1,185 @@
+use crate::wasm::{ExecutionLimits, WASM_INSTANCE_MEMORY_LIMIT, WASM_MINIMUM_MEMORY_SIZE};
+use serde::Deserialize;
+use std::time::Duration;
+
+fn default_memory() -> u64 {
+    WASM_INSTANCE_MEMORY_LIMIT
+}
+
+fn default_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_max_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_idle_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_max_session_ms() -> u64 {
+    300_000
+}
+
+fn default_max_source_size() -> usize {
+    bytesize::KIB as usize * 64
+}
+
+fn default_max_request_size() -> usize {
+    bytesize::KIB as usize * 256
+}
+
+fn default_output() -> u64 {
+    bytesize::MIB
+}
+
+fn default_max_output() -> u64 {
+    bytesize::MIB * 4
+}
+
+/// Server-wide bounds for run limits, read from `LIMITS_*` environment variables.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LimitsConfig {
+    /// Linear memory per run in bytes.
+    #[serde(default = "default_memory")]
+    pub default_memory: u64,
+    #[serde(default = "default_memory")]
+    pub max_memory: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub default_timeout_ms: u64,
+    #[serde(default = "default_max_timeout_ms")]
+    pub max_timeout_ms: u64,
+    /// How long an interactive run may wait for the next line of input.
+    #[serde(default = "default_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+    /// How long an interactive run may last in total, however often the
+    /// client sends input.
+    #[serde(default = "default_max_session_ms")]
+    pub max_session_ms: u64,
+    /// Program output per run in bytes.
+    #[serde(default = "default_output")]
+    pub default_output: u64,
+    #[serde(default = "default_max_output")]
+    pub max_output: u64,
+    /// Size of a single source file in bytes.
+    #[serde(default = "default_max_source_size")]
+    pub max_source_size: usize,
+    /// Size of a whole request body in bytes, which bounds the total size of
+    /// all files it carries.
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            default_memory: default_memory(),
+            max_memory: default_memory(),
+            default_timeout_ms: default_timeout_ms(),
+            max_timeout_ms: default_max_timeout_ms(),
+            idle_timeout_ms: default_idle_timeout_ms(),
+            max_session_ms: default_max_session_ms(),
+            default_output: default_output(),
+            max_output: default_max_output(),
+            max_source_size: default_max_source_size(),
+            max_request_size: default_max_request_size(),
+        }
+    }
+}
+
+/// Limits a client asks for. Unset ones fall back to the server defaults.
+#[derive(Deserialize, Debug, Default)]
+pub struct RequestedLimits {
+    pub memory: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub output: Option<u64>,
+}
+
+fn pick(
+    name: &str,
+    requested: Option<u64>,
+    default: u64,
+    min: u64,
+    max: u64,
+) -> Result<u64, String> {
+    let value = requested.unwrap_or(default);
+    if value < min {
+        return Err(format!(
+            "{name} limit of {value} is below the minimum of {min}"
+        ));
+    }
+    if value > max {
+        return Err(format!(
+            "{name} limit of {value} exceeds the maximum of {max}"
+        ));
+    }
+    Ok(value)
+}
+
+impl LimitsConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.resolve(&RequestedLimits::default(), None)
+            .map_err(|e| anyhow::anyhow!("invalid default limits: {e}"))?;
+        if self.max_source_size > self.max_request_size {
+            anyhow::bail!("maximum source size exceeds the maximum request size");
+        }
+        Ok(())
+    }
+
+    pub fn max_timeout(&self) -> Duration {
+        Duration::from_millis(self.max_timeout_ms)
+    }
+
+    pub fn max_session(&self) -> Duration {
+        Duration::from_millis(self.max_session_ms)
+    }
+
+    pub fn check_source(&self, code: &str) -> Result<(), String> {
+        if code.len() > self.max_source_size {
+            return Err(format!(
+                "source code of {} bytes exceeds the maximum of {} bytes",
+                code.len(),
+                self.max_source_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks requested limits against the server maxima.
+    pub fn resolve(
+        &self,
+        requested: &RequestedLimits,
+        fuel: Option<u64>,
+    ) -> Result<ExecutionLimits, String> {
+        let memory = pick(
+            "memory",
+            requested.memory,
+            self.default_memory,
+            WASM_MINIMUM_MEMORY_SIZE,
+            self.max_memory,
+        )?;
+        let timeout_ms = pick(
+            "timeout",
+            requested.timeout_ms,
+            self.default_timeout_ms,
+            1,
+            self.max_timeout_ms,
+        )?;
+        let output = pick(
+            "output",
+            requested.output,
+            self.default_output,
+            1,
+            self.max_output,
+        )?;
+
+        Ok(ExecutionLimits {
+            memory,
+            timeout: Duration::from_millis(timeout_ms),
+            idle_timeout: Duration::from_millis(self.idle_timeout_ms),
+            output,
+            fuel,
+        })
+    }
+}
@@ -1,29 +1,69 @@
+mod archive;
+mod cache;
 mod error;
 mod handler;
+mod input;
+mod jobs;
+mod limits;
+mod markdown;
+mod output;
+mod rate_limit;
+mod scheduler;
+mod snippets;
+mod source;
 mod static_server;
 mod telemetry;
 mod wasm;
 
-use crate::error::SandboxError;
+use crate::cache::{BuildCache, CacheConfig};
+use crate::error::{Rejection, SandboxError};
+use crate::jobs::{JobKind, JobOptions, JobTable, JobsConfig};
+use crate::limits::{LimitsConfig, RequestedLimits};
+use crate::rate_limit::{RateLimitConfig, RateLimiter};
+use crate::scheduler::{ClientId, Scheduler, SchedulerConfig};
+use crate::snippets::{
+    RevisionOptions, Snippet, SnippetRunOptions, SnippetStore, SnippetsConfig, StoredResult,
+};
+use crate::source::{RawBody, SourceCode};
+use crate::static_server::{EmbedOptions, PagesConfig};
 use axum::{
     error_handling::HandleErrorLayer,
-    http::{header, HeaderValue, Request, StatusCode},
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query,
+    },
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::post,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
     Extension, Json, Router,
 };
+use bytes::Bytes;
 use dotenv::dotenv;
-use handler::HandlerResponse;
+use handler::{BuildOptions, HandlerResponse, RunEvent};
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::{convert::Infallible, sync::Arc};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
 use tower_http::trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer};
-use tracing::{instrument, subscriber::set_global_default, Level};
-use wasm::create_interruptable_engine;
+use tracing::{instrument, subscriber::set_global_default, Instrument, Level};
+use wasm::{create_interruptable_engine, EngineConfig};
 
 const MAX_AGE_ONE_HOUR: HeaderValue = HeaderValue::from_static("public, max-age=3600");
 const MAX_AGE_ONE_YEAR: HeaderValue = HeaderValue::from_static("public, max-age=31536000");
+const SAME_ORIGIN: HeaderValue = HeaderValue::from_static("SAMEORIGIN");
+const SAME_FRAME_ANCESTOR: HeaderValue = HeaderValue::from_static("frame-ancestors 'self'");
+/// Messages of input an interactive run buffers before the client has to
+/// wait for the program to read them.
+const STDIN_BUFFER: usize = 8;
+/// WebSocket close code for clients that break the rules, from RFC 6455.
+const POLICY_VIOLATION: u16 = 1008;
+/// Size of embedded snippets when the consumer doesn't ask for less.
+const OEMBED_WIDTH: u32 = 800;
 
 #[derive(Deserialize, Debug)]
 struct EnvConfig {
@@ -35,6 +75,13 @@ struct EnvConfig {
 
 pub struct State {
     engine: wasmtime::Engine,
+    limits: LimitsConfig,
+    fuel: Option<u64>,
+    build_cache: Option<BuildCache>,
+    scheduler: Scheduler,
+    rate_limiter: RateLimiter,
+    jobs: Arc<JobTable>,
+    snippets: SnippetStore,
 }
 
 impl IntoResponse for SandboxError {
@@ -59,15 +106,70 @@ async fn main() {
         set_global_default(subscriber).expect("failed to set global subscriber");
     };
 
-    let engine = create_interruptable_engine();
-    let state = Arc::new(State { engine });
+    let cache_config = envy::prefixed("BUILD_CACHE_")
+        .from_env::<CacheConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+    let build_cache = if cache_config.enabled {
+        let cache = BuildCache::new(cache_config)
+            .await
+            .expect("failed to initialize build cache");
+        Some(cache)
+    } else {
+        None
+    };
+
+    let engine_config = envy::prefixed("ENGINE_")
+        .from_env::<EngineConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+    let limits = envy::prefixed("LIMITS_")
+        .from_env::<LimitsConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+    limits
+        .validate()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+
+    let scheduler_config = envy::prefixed("SCHEDULER_")
+        .from_env::<SchedulerConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+
+    let rate_limit_config = envy::prefixed("RATE_LIMIT_")
+        .from_env::<RateLimitConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+
+    let jobs_config = envy::prefixed("JOBS_")
+        .from_env::<JobsConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+
+    let snippets_config = envy::prefixed("SNIPPETS_")
+        .from_env::<SnippetsConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+    let snippets = SnippetStore::new(snippets_config).expect("failed to initialize snippet store");
+
+    let engine = create_interruptable_engine(&engine_config, limits.max_memory);
+    let state = Arc::new(State {
+        engine,
+        limits,
+        fuel: engine_config.fuel,
+        build_cache,
+        scheduler: Scheduler::new(&scheduler_config),
+        rate_limiter: RateLimiter::new(rate_limit_config),
+        jobs: Arc::new(JobTable::new(&jobs_config)),
+        snippets,
+    });
+
+    let pages_config = envy::prefixed("PAGES_")
+        .from_env::<PagesConfig>()
+        .unwrap_or_else(|error| panic!("{:#?}", error));
+    static_server::init(pages_config).expect("failed to render pages");
 
     let static_service = static_server::file_service(MAX_AGE_ONE_HOUR, MAX_AGE_ONE_YEAR);
 
     let app = Router::new()
         .fallback(static_service)
-        .route("/api/run", post(run))
-        .route("/api/build", post(build))
+        .merge(api())
+        .route("/s/:id", get(snippet_page))
+        .route("/embed/:id", get(embed_snippet))
+        .route("/api/oembed", get(oembed))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
@@ -76,13 +178,32 @@ async fn main() {
         )
         .layer(Extension(state))
         .layer(HandleErrorLayer::new(handle_error))
-        .layer(middleware::from_fn(uncache_404));
+        .layer(middleware::from_fn(uncache_404))
+        .layer(middleware::from_fn(restrict_framing));
 
     serve(app, env.ip_addr)
         .await
         .expect("server crashed unexpectedly");
 }
 
+fn api() -> Router {
+    Router::new()
+        .route("/api/run", post(run))
+        .route("/api/run/stream", post(run_stream))
+        .route("/api/run/interactive", get(run_interactive))
+        .route("/api/build", post(build))
+        .route("/api/jobs", post(create_job))
+        .route("/api/snippets", post(create_snippet))
+        .route("/api/snippets/:id/run", post(run_snippet))
+        .route("/api/snippets/import", post(import_snippet))
+        .route_layer(middleware::from_fn(rate_limit::enforce))
+        // Cheap enough to go without a quota, which polling a job would use up.
+        .route("/api/jobs/:id", get(get_job).merge(delete(cancel_job)))
+        .route("/api/snippets/:id", get(get_snippet))
+        .route("/api/snippets/:id/history", get(get_snippet_history))
+        .route("/api/snippets/:id/export", get(export_snippet))
+}
+
 async fn uncache_404<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
     let mut res = next.run(req).await;
     if res.status() == StatusCode::NOT_FOUND {
@@ -94,43 +215,681 @@ async fn uncache_404<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
     res
 }
 
+/// Pages may only be framed by the playground itself, unless they set their
+/// own policy like [`embed_snippet`] does.
+async fn restrict_framing<B>(req: Request<B>, next: Next<B>) -> Response {
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+    if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        headers.insert(header::X_FRAME_OPTIONS, SAME_ORIGIN);
+        headers.insert(header::CONTENT_SECURITY_POLICY, SAME_FRAME_ANCESTOR);
+    }
+    res
+}
+
 #[instrument(skip_all, name = "Invoke build handler", fields(
     service.name = "typerust"
 ))]
-async fn build(code: String) -> impl IntoResponse {
-    let response = handler::build(code).await?;
-    Ok::<_, SandboxError>(Json(response))
+async fn build(
+    Query(options): Query<BuildOptions>,
+    Extension(client): Extension<ClientId>,
+    Extension(state): Extension<Arc<State>>,
+    SourceCode(code): SourceCode,
+) -> Response {
+    match handler::build(code, options, client, state.clone()).await {
+        Err(SandboxError::QueueFull) => {
+            tracing::warn!("job queue is full");
+            error_response(StatusCode::SERVICE_UNAVAILABLE, &state)
+        }
+        result => result.map(Json).into_response(),
+    }
 }
 
 #[instrument(skip_all, name = "Invoke run handler", fields(
     service.name = "typerust"
 ))]
-async fn run(code: String, Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
-    match handler::run(code, state).await {
+async fn run(
+    Query(options): Query<BuildOptions>,
+    Query(requested_limits): Query<RequestedLimits>,
+    Extension(client): Extension<ClientId>,
+    Extension(state): Extension<Arc<State>>,
+    SourceCode(code): SourceCode,
+) -> impl IntoResponse {
+    let result = handler::run(code, options, requested_limits, client, state.clone()).await;
+    run_response(result)
+        .map(Json)
+        .map_err(|status| error_response(status, &state))
+}
+
+#[instrument(skip_all, name = "Invoke streaming run handler", fields(
+    service.name = "typerust"
+))]
+async fn run_stream(
+    Query(options): Query<BuildOptions>,
+    Query(requested_limits): Query<RequestedLimits>,
+    Extension(client): Extension<ClientId>,
+    Extension(state): Extension<Arc<State>>,
+    SourceCode(code): SourceCode,
+) -> impl IntoResponse {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let task = async move {
+        let run = handler::run_streaming(
+            code,
+            options,
+            requested_limits,
+            client,
+            state,
+            sender.clone(),
+        );
+        tokio::select! {
+            result = run => {
+                let _ = sender.send(done_event(result));
+            }
+            // The event stream is dropped once the client disconnects.
+            _ = sender.closed() => {}
+        }
+    };
+    tokio::spawn(task.in_current_span());
+
+    let events =
+        UnboundedReceiverStream::new(receiver).map(|event| Event::default().json_data(event));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[instrument(skip_all, name = "Invoke job creation handler", fields(
+    service.name = "typerust"
+))]
+async fn create_job(
+    Query(job_options): Query<JobOptions>,
+    Query(options): Query<BuildOptions>,
+    Query(requested_limits): Query<RequestedLimits>,
+    Extension(client): Extension<ClientId>,
+    Extension(state): Extension<Arc<State>>,
+    SourceCode(code): SourceCode,
+) -> Response {
+    let jobs = state.jobs.clone();
+    let retry_after = state.scheduler.retry_after();
+    let submitted = jobs.submit(move |events| async move {
+        let result = match job_options.kind {
+            JobKind::Run => {
+                handler::run_streaming(code, options, requested_limits, client, state, events).await
+            }
+            JobKind::Build => handler::build_streaming(code, options, client, state, events).await,
+        };
+        final_response(result)
+    });
+
+    match submitted {
+        Ok(id) => {
+            let created = serde_json::json!({ "id": id });
+            (StatusCode::ACCEPTED, Json(created)).into_response()
+        }
+        Err(e) => {
+            tracing::warn!("{e}");
+            Rejection {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                error: "too_many_jobs",
+                message: "Too many jobs are in progress, try again later".into(),
+                retry_after: Some(retry_after),
+            }
+            .into_response()
+        }
+    }
+}
+
+async fn get_job(Path(id): Path<String>, Extension(state): Extension<Arc<State>>) -> Response {
+    match state.jobs.get(&id) {
+        Some(report) => Json(report).into_response(),
+        None => job_not_found(),
+    }
+}
+
+async fn cancel_job(Path(id): Path<String>, Extension(state): Extension<Arc<State>>) -> Response {
+    if state.jobs.cancel(&id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        job_not_found()
+    }
+}
+
+fn job_not_found() -> Response {
+    Rejection {
+        status: StatusCode::NOT_FOUND,
+        error: "job_not_found",
+        message: "No such job, it may have expired".into(),
+        retry_after: None,
+    }
+    .into_response()
+}
+
+#[instrument(skip_all, name = "Invoke snippet creation handler", fields(
+    service.name = "typerust"
+))]
+async fn create_snippet(
+    Query(options): Query<BuildOptions>,
+    Query(revision): Query<RevisionOptions>,
+    Extension(state): Extension<Arc<State>>,
+    SourceCode(code): SourceCode,
+) -> Response {
+    let (parent, forked_from) = match (revision.parent, revision.fork) {
+        (Some(_), Some(_)) => {
+            return Rejection {
+                status: StatusCode::BAD_REQUEST,
+                error: "invalid_revision",
+                message: "A revision can't both continue and fork a snippet".into(),
+                retry_after: None,
+            }
+            .into_response()
+        }
+        revision => revision,
+    };
+    // Revisions may only point to snippets that exist.
+    for id in parent.iter().chain(&forked_from) {
+        match state.snippets.get(id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return snippet_not_found(),
+            Err(e) => return snippet_store_error(e),
+        }
+    }
+
+    let snippet = Snippet {
+        code,
+        options,
+        parent,
+        forked_from,
+    };
+    save_snippet(&state, &snippet).await
+}
+
+async fn save_snippet(state: &State, snippet: &Snippet) -> Response {
+    match state.snippets.put(snippet).await {
+        Ok(id) => {
+            let created = serde_json::json!({ "id": id, "url": format!("/s/{id}") });
+            (StatusCode::CREATED, Json(created)).into_response()
+        }
+        Err(e) => snippet_store_error(e),
+    }
+}
+
+/// A Cargo project to build the snippet locally, see [`archive::export`].
+async fn export_snippet(
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let snippet = match state.snippets.get(&id).await {
+        Ok(Some(snippet)) => snippet,
+        Ok(None) => return snippet_not_found(),
+        Err(e) => return snippet_store_error(e),
+    };
+    let toolchain = handler::toolchain_version().await;
+    match archive::export(&id, &snippet, &toolchain) {
+        Ok(bytes) => {
+            let disposition = format!("attachment; filename=\"playground-{id}.tar.gz\"");
+            let headers = [
+                (header::CONTENT_TYPE, "application/gzip".to_owned()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ];
+            (headers, bytes).into_response()
+        }
+        Err(e) => {
+            tracing::error!("failed to export snippet: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Stores the snippet in an archive made by [`export_snippet`] as a new one.
+#[instrument(skip_all, name = "Invoke snippet import handler", fields(
+    service.name = "typerust"
+))]
+async fn import_snippet(
+    Extension(state): Extension<Arc<State>>,
+    RawBody(bytes): RawBody,
+) -> Response {
+    let snippet = match archive::import(&bytes) {
+        Ok(snippet) => snippet,
+        Err(e) => {
+            return Rejection {
+                status: StatusCode::BAD_REQUEST,
+                error: "invalid_archive",
+                message: e.to_string(),
+                retry_after: None,
+            }
+            .into_response()
+        }
+    };
+    let code = match SourceCode::check(&state.limits, snippet.code) {
+        Ok(SourceCode(code)) => code,
+        Err(rejection) => return rejection.into_response(),
+    };
+    save_snippet(&state, &Snippet { code, ..snippet }).await
+}
+
+async fn get_snippet(Path(id): Path<String>, Extension(state): Extension<Arc<State>>) -> Response {
+    let snippet = match state.snippets.get(&id).await {
+        Ok(Some(snippet)) => snippet,
+        Ok(None) => return snippet_not_found(),
+        Err(e) => return snippet_store_error(e),
+    };
+    // A result that can't be read just isn't shown.
+    let result = state.snippets.get_result(&id).await.unwrap_or_else(|e| {
+        tracing::warn!("failed to load snippet result: {e:#}");
+        None
+    });
+
+    let mut body = serde_json::to_value(snippet).expect("snippets serialize to JSON");
+    if let Some(result) = result {
+        body["result"] = serde_json::to_value(result).expect("results serialize to JSON");
+    }
+    Json(body).into_response()
+}
+
+async fn get_snippet_history(
+    Path(id): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    match state.snippets.history(&id).await {
+        Ok(Some(revisions)) => Json(serde_json::json!({ "revisions": revisions })).into_response(),
+        Ok(None) => snippet_not_found(),
+        Err(e) => snippet_store_error(e),
+    }
+}
+
+/// Runs the stored code again and tells whether the output is still the same
+/// as the one stored with the snippet.
+#[instrument(skip_all, name = "Invoke snippet run handler", fields(
+    service.name = "typerust"
+))]
+async fn run_snippet(
+    Path(id): Path<String>,
+    Query(run_options): Query<SnippetRunOptions>,
+    Extension(client): Extension<ClientId>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let snippet = match state.snippets.get(&id).await {
+        Ok(Some(snippet)) => snippet,
+        Ok(None) => return snippet_not_found(),
+        Err(e) => return snippet_store_error(e),
+    };
+
+    let result = handler::run(
+        snippet.code,
+        snippet.options,
+        RequestedLimits::default(),
+        client,
+        state.clone(),
+    )
+    .await;
+    let response = match run_response(result) {
+        Ok(response) => response,
+        Err(status) => return error_response(status, &state),
+    };
+
+    let previous = state.snippets.get_result(&id).await.unwrap_or_else(|e| {
+        tracing::warn!("failed to load snippet result: {e:#}");
+        None
+    });
+    let matches = previous
+        .as_ref()
+        .map(|previous| previous.response.same_output(&response));
+    let result = StoredResult {
+        toolchain: handler::toolchain_version().await,
+        response,
+    };
+    let mut saved = false;
+    if run_options.save && previous.is_none() && result.response.is_reproducible() {
+        saved = match state.snippets.put_result(&id, &result).await {
+            Ok(saved) => saved,
+            Err(e) => return snippet_store_error(e),
+        };
+    }
+
+    let body = serde_json::json!({
+        "result": result,
+        "previous": previous,
+        "matches": matches,
+        "saved": saved,
+    });
+    Json(body).into_response()
+}
+
+/// The playground itself, which loads the snippet through [`get_snippet`].
+async fn snippet_page(Path(id): Path<String>, Extension(state): Extension<Arc<State>>) -> Response {
+    match state.snippets.get(&id).await {
+        Ok(Some(_)) => static_server::index().into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => snippet_store_error(e),
+    }
+}
+
+/// Scheme and host the client used to reach the playground.
+fn request_origin(headers: &HeaderMap) -> String {
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .filter(|scheme| *scheme == "https")
+        .unwrap_or("http");
+    // The host ends up in HTML, so anything unusual is ignored.
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .filter(|host| {
+            host.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b':'))
+        })
+        .unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
+
+async fn embed_snippet(
+    Path(id): Path<String>,
+    Query(options): Query<EmbedOptions>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let snippet = match state.snippets.get(&id).await {
+        Ok(Some(snippet)) => snippet,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return snippet_store_error(e),
+    };
+    let result = state.snippets.get_result(&id).await.unwrap_or_else(|e| {
+        tracing::warn!("failed to load snippet result: {e:#}");
+        None
+    });
+    let origin = request_origin(&headers);
+    let link = format!("{origin}/s/{id}");
+    let oembed_url = format!(
+        "/api/oembed?url={}",
+        url::form_urlencoded::byte_serialize(link.as_bytes()).collect::<String>()
+    );
+    static_server::embed_page(&id, &snippet, result.as_ref(), &options, &oembed_url).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct OEmbedQuery {
+    url: String,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<String>,
+}
+
+/// ID of the snippet a link points to, if it is a link to the playground or
+/// the embed page on this site.
+fn linked_snippet(link: &str, origin: &str) -> Option<String> {
+    let link = url::Url::parse(link).ok()?;
+    if link.origin() != url::Url::parse(origin).ok()?.origin() {
+        return None;
+    }
+    let mut segments = link.path_segments()?;
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("s" | "embed"), Some(id), None) if !id.is_empty() => Some(id.to_owned()),
+        _ => None,
+    }
+}
+
+/// Tells sites that understand oEmbed how to embed a snippet from its link.
+/// See <https://oembed.com>.
+async fn oembed(
+    Query(query): Query<OEmbedQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    if query
+        .format
+        .as_deref()
+        .is_some_and(|format| format != "json")
+    {
+        return StatusCode::NOT_IMPLEMENTED.into_response();
+    }
+    let origin = request_origin(&headers);
+    let Some(id) = linked_snippet(&query.url, &origin) else {
+        return snippet_not_found();
+    };
+    match state.snippets.get(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return snippet_not_found(),
+        Err(e) => return snippet_store_error(e),
+    }
+
+    let default_height = EmbedOptions::default().height();
+    let height = query
+        .maxheight
+        .map_or(default_height, |max| max.min(default_height));
+    let width = query
+        .maxwidth
+        .map_or(OEMBED_WIDTH, |max| max.min(OEMBED_WIDTH));
+    let html = format!(
+        "<iframe src=\"{origin}/embed/{id}?height={height}\" width=\"{width}\" height=\"{height}\" \
+         frameborder=\"0\" loading=\"lazy\"></iframe>"
+    );
+    Json(serde_json::json!({
+        "version": "1.0",
+        "type": "rich",
+        "provider_name": "TypeRust",
+        "provider_url": origin,
+        "title": format!("TypeRust snippet {id}"),
+        "html": html,
+        "width": width,
+        "height": height,
+    }))
+    .into_response()
+}
+
+fn snippet_store_error(e: anyhow::Error) -> Response {
+    tracing::error!("snippet store failed: {e:#}");
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+fn snippet_not_found() -> Response {
+    Rejection {
+        status: StatusCode::NOT_FOUND,
+        error: "snippet_not_found",
+        message: "No such snippet".into(),
+        retry_after: None,
+    }
+    .into_response()
+}
+
+/// Messages a client sends over an interactive session.
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "data")]
+enum ClientMessage {
+    /// Starts the run. Must be the first message.
+    Run(String),
+    /// Text fed to the guest's stdin as is, usually a line ending in `\n`.
+    Stdin(String),
+    /// Closes the guest's stdin.
+    Eof,
+}
+
+#[instrument(skip_all, name = "Invoke interactive run handler", fields(
+    service.name = "typerust"
+))]
+async fn run_interactive(
+    Query(options): Query<BuildOptions>,
+    Query(requested_limits): Query<RequestedLimits>,
+    Extension(client): Extension<ClientId>,
+    Extension(state): Extension<Arc<State>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.max_message_size(state.limits.max_request_size)
+        .on_upgrade(move |socket| {
+            interactive_session(socket, options, requested_limits, client, state).in_current_span()
+        })
+}
+
+/// Skips messages that aren't understood. `None` once the client is gone.
+async fn receive_message(socket: &mut WebSocket) -> Option<ClientMessage> {
+    while let Some(Ok(message)) = socket.recv().await {
+        match message {
+            Message::Text(text) => match serde_json::from_str(&text) {
+                Ok(message) => return Some(message),
+                Err(e) => tracing::debug!("ignoring malformed client message: {e}"),
+            },
+            Message::Close(_) => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Sends the same events as [`run_stream`] as JSON text messages and feeds
+/// `Stdin` messages to the guest until the run is done.
+async fn interactive_session(
+    mut socket: WebSocket,
+    options: BuildOptions,
+    requested_limits: RequestedLimits,
+    client: ClientId,
+    state: Arc<State>,
+) {
+    // Nothing runs yet, but the connection still counts against the limits.
+    let first = tokio::time::timeout(state.limits.max_timeout(), receive_message(&mut socket));
+    let code = match first.await {
+        Ok(Some(ClientMessage::Run(code))) => code,
+        Ok(_) => return,
+        Err(_) => {
+            let frame = CloseFrame {
+                code: POLICY_VIOLATION,
+                reason: "no code was sent in time".into(),
+            };
+            let _ = socket.send(Message::Close(Some(frame))).await;
+            return;
+        }
+    };
+    if let Err(message) = state.limits.check_source(&code) {
+        let event = RunEvent::Done(HandlerResponse::Error(message));
+        let text = serde_json::to_string(&event).expect("events serialize to JSON");
+        let _ = socket.send(Message::Text(text)).await;
+        let _ = socket.close().await;
+        return;
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let (stdin_sender, stdin_receiver) = mpsc::channel(STDIN_BUFFER);
+    let max_session = state.limits.max_session();
+    let task = async move {
+        let run = handler::run_interactive(
+            code,
+            options,
+            requested_limits,
+            client,
+            state,
+            sender.clone(),
+            stdin_receiver,
+        );
+        // Input resets the idle timeout, so this is what frees the slot of a
+        // client that keeps typing.
+        let event = match tokio::time::timeout(max_session, run).await {
+            Ok(result) => done_event(result),
+            Err(_) => RunEvent::Done(HandlerResponse::Error(format!(
+                "RUNTIME ERROR: Your session lasted longer than {} seconds and was closed",
+                max_session.as_secs()
+            ))),
+        };
+        let _ = sender.send(event);
+    };
+    let task = tokio::spawn(task.in_current_span());
+
+    let mut stdin = Some(stdin_sender);
+    // Input the program has no room for yet. The socket isn't read until it
+    // is passed on, so a fast client can't make the server buffer more.
+    let mut pending: Option<Bytes> = None;
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else { break };
+                let done = matches!(event, RunEvent::Done(_));
+                let text = serde_json::to_string(&event).expect("events serialize to JSON");
+                if socket.send(Message::Text(text)).await.is_err() || done {
+                    break;
+                }
+            }
+            permit = reserve_stdin(stdin.clone()), if pending.is_some() => match (permit, pending.take()) {
+                (Some(permit), Some(bytes)) => {
+                    permit.send(bytes);
+                }
+                // The program is done reading.
+                _ => stdin = None,
+            },
+            message = receive_message(&mut socket), if pending.is_none() => match message {
+                Some(ClientMessage::Stdin(text)) => {
+                    if stdin.is_some() {
+                        pending = Some(Bytes::from(text));
+                    }
+                }
+                Some(ClientMessage::Eof) => stdin = None,
+                Some(ClientMessage::Run(_)) => {}
+                None => break,
+            },
+        }
+    }
+
+    // Nobody is left to see the result if the client is gone.
+    task.abort();
+    let _ = socket.close().await;
+}
+
+/// Room for one more message of input, `None` once stdin is closed.
+async fn reserve_stdin(stdin: Option<mpsc::Sender<Bytes>>) -> Option<mpsc::OwnedPermit<Bytes>> {
+    stdin?.reserve_owned().await.ok()
+}
+
+fn done_event(result: Result<HandlerResponse, SandboxError>) -> RunEvent {
+    RunEvent::Done(final_response(result))
+}
+
+/// The response status is already sent when streaming or polling, so
+/// failures become part of the response.
+fn final_response(result: Result<HandlerResponse, SandboxError>) -> HandlerResponse {
+    run_response(result).unwrap_or_else(|status| {
+        HandlerResponse::Error(format!("The server failed to run your code: {status}"))
+    })
+}
+
+fn run_response(
+    result: Result<HandlerResponse, SandboxError>,
+) -> Result<HandlerResponse, StatusCode> {
+    match result {
         Err(SandboxError::Internal(_)) => {
             tracing::error!("unexpected internal error");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
-        Err(SandboxError::Timeout) => {
-            let response = HandlerResponse::Error(
-                "RUNTIME ERROR: Your code took too long to execute and was interrupted".into(),
-            );
-            Ok(Json(response))
+        Err(SandboxError::Busy) => {
+            tracing::warn!("all sandbox slots are in use");
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+        Err(SandboxError::QueueFull) => {
+            tracing::warn!("job queue is full");
+            Err(StatusCode::SERVICE_UNAVAILABLE)
         }
+        Err(SandboxError::FuelExhausted) => Ok(HandlerResponse::Error(
+            "RUNTIME ERROR: Your code used up its instruction budget and was interrupted".into(),
+        )),
+        Err(SandboxError::Timeout) => Ok(HandlerResponse::Error(
+            "RUNTIME ERROR: Your code took too long to execute and was interrupted".into(),
+        )),
         Err(_) => {
             tracing::error!("memory limit or unallowed filesystem/network access");
-            let response = HandlerResponse::Error(
+            Ok(HandlerResponse::Error(
                 "RUNTIME ERROR: This could happen for the following reasons:
 a) you tried to access filesystem and/or network which is not allowed in this playground or
 b) your code exceeded memory limit and was interrupted."
                     .into(),
-            );
-            Ok(Json(response))
+            ))
         }
-        Ok(resp) => Ok(Json(resp)),
+        Ok(resp) => Ok(resp),
     }
 }
 
+/// Asks clients to come back later when the server is too busy.
+fn error_response(status: StatusCode, state: &State) -> Response {
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = HeaderValue::from(state.scheduler.retry_after());
+        return (status, [(header::RETRY_AFTER, retry_after)]).into_response();
+    }
+    status.into_response()
+}
+
 async fn handle_error(_: Infallible) -> (StatusCode, String) {
     (
         StatusCode::INTERNAL_SERVER_ERROR,
@@ -141,7 +900,190 @@ async fn handle_error(_: Infallible) -> (StatusCode, String) {
 async fn serve(app: Router, addr: SocketAddr) -> Result<(), anyhow::Error> {
     println!("listening on {addr}...");
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::tests::test_state;
+    use crate::wasm::EngineConfig;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    async fn embed_app() -> (Router, String) {
+        let config = PagesConfig {
+            templates: concat!(env!("CARGO_MANIFEST_DIR"), "/../templates/**/*").to_owned(),
+            ..PagesConfig::default()
+        };
+        // Tests share the rendered pages, only the first one sets them up.
+        let _ = static_server::init(config);
+
+        let state = test_state(EngineConfig::default(), None);
+        let snippet = Snippet {
+            code: "fn main() {}".into(),
+            options: BuildOptions::default(),
+            parent: None,
+            forked_from: None,
+        };
+        let id = state.snippets.put(&snippet).await.unwrap();
+        let app = Router::new()
+            .route("/s/:id", get(snippet_page))
+            .route("/embed/:id", get(embed_snippet))
+            .route("/api/oembed", get(oembed))
+            .layer(Extension(state))
+            .layer(middleware::from_fn(restrict_framing));
+        (app, id)
+    }
+
+    async fn send(app: &Router, uri: &str) -> Response {
+        let request = Request::get(uri)
+            .header(header::HOST, "play.example")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = http_body::Body::data(&mut body).await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn poll_jobs_without_using_up_the_quota() {
+        let state = test_state(EngineConfig::default(), None);
+        let burst = RateLimitConfig::default().burst;
+        let app = api().layer(Extension(state));
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from("fn main() {}"))
+                .unwrap()
+        };
+
+        for _ in 0..=burst {
+            let poll = app
+                .clone()
+                .oneshot(request("GET", "/api/jobs/1"))
+                .await
+                .unwrap();
+            assert_eq!(poll.status(), StatusCode::NOT_FOUND);
+            let history = request("GET", "/api/snippets/0123456789/history");
+            let history = app.clone().oneshot(history).await.unwrap();
+            assert_eq!(history.status(), StatusCode::NOT_FOUND);
+        }
+
+        let mut statuses = Vec::new();
+        for _ in 0..=burst {
+            let save = app
+                .clone()
+                .oneshot(request("POST", "/api/snippets"))
+                .await
+                .unwrap();
+            statuses.push(save.status());
+        }
+        assert_eq!(statuses.last(), Some(&StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    #[tokio::test]
+    async fn only_store_reproducible_results() {
+        let state = test_state(EngineConfig::default(), None);
+        let snippet = Snippet {
+            code: "fn main() { loop {} }".into(),
+            options: BuildOptions::default(),
+            parent: None,
+            forked_from: None,
+        };
+        let id = state.snippets.put(&snippet).await.unwrap();
+        let app = api().layer(Extension(state.clone()));
+
+        let request = Request::post(format!("/api/snippets/{id}/run?save=true"))
+            .body(Body::empty())
+            .unwrap();
+        let run = json(app.oneshot(request).await.unwrap()).await;
+        assert!(run["result"]["response"]["data"]
+            .as_str()
+            .unwrap()
+            .contains("took too long"));
+        assert_eq!(run["saved"], false);
+        assert!(state.snippets.get_result(&id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn only_embeds_may_be_framed_anywhere() {
+        let (app, id) = embed_app().await;
+
+        let embed = send(&app, &format!("/embed/{id}")).await;
+        assert_eq!(embed.status(), StatusCode::OK);
+        assert_eq!(
+            embed.headers()[header::CONTENT_SECURITY_POLICY],
+            "frame-ancestors *"
+        );
+        assert!(!embed.headers().contains_key(header::X_FRAME_OPTIONS));
+
+        let page = send(&app, &format!("/s/{id}")).await;
+        assert_eq!(page.status(), StatusCode::OK);
+        assert_eq!(page.headers()[header::X_FRAME_OPTIONS], SAME_ORIGIN);
+        assert_eq!(
+            page.headers()[header::CONTENT_SECURITY_POLICY],
+            SAME_FRAME_ANCESTOR
+        );
+    }
+
+    #[tokio::test]
+    async fn describe_snippets_with_oembed() {
+        let (app, id) = embed_app().await;
+        let oembed_uri = |link: &str, params: &str| {
+            let link = url::form_urlencoded::byte_serialize(link.as_bytes()).collect::<String>();
+            format!("/api/oembed?url={link}{params}")
+        };
+
+        for link in [
+            format!("http://play.example/s/{id}"),
+            format!("http://play.example/embed/{id}?theme=light"),
+        ] {
+            let response = send(&app, &oembed_uri(&link, "")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let oembed = json(response).await;
+            assert_eq!(oembed["type"], "rich");
+            assert_eq!(oembed["width"], OEMBED_WIDTH);
+            assert_eq!(oembed["height"], 400);
+            let src = format!("src=\"http://play.example/embed/{id}?height=400\"");
+            assert!(oembed["html"].as_str().unwrap().contains(&src));
+        }
+
+        let link = format!("http://play.example/s/{id}");
+        let response = send(&app, &oembed_uri(&link, "&maxwidth=300&maxheight=200")).await;
+        let oembed = json(response).await;
+        assert_eq!(
+            (oembed["width"].as_u64(), oembed["height"].as_u64()),
+            (Some(300), Some(200))
+        );
+        let response = send(&app, &oembed_uri(&link, "&maxwidth=5000&maxheight=5000")).await;
+        let oembed = json(response).await;
+        assert_eq!(
+            (oembed["width"].as_u64(), oembed["height"].as_u64()),
+            (Some(800), Some(400))
+        );
+
+        let response = send(&app, &oembed_uri(&link, "&format=xml")).await;
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+
+        for link in [
+            format!("https://evil.example/s/{id}"),
+            format!("http://play.example/other/{id}"),
+            format!("http://play.example/s/{id}/history"),
+            "http://play.example/s/0000000000".to_owned(),
+        ] {
+            let response = send(&app, &oembed_uri(&link, "")).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{link}");
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use once_cell::sync::Lazy;
+use pulldown_cmark::escape::escape_html;
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Parser, Tag};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+type FrontMatterParser = fn(&str) -> anyhow::Result<FrontMatter>;
+
+/// Front matter is TOML between `+++` lines or YAML between `---` lines.
+const FRONT_MATTER_FORMATS: [(&str, FrontMatterParser); 2] = [
+    ("+++", |text| Ok(toml::from_str(text)?)),
+    ("---", |text| Ok(serde_yaml::from_str(text)?)),
+];
+
+/// Page metadata given before the content.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct FrontMatter {
+    pub title: Option<String>,
+    /// Shown to search engines and link previews.
+    pub description: Option<String>,
+    /// Pages are listed by this in the navigation, lowest first.
+    pub order: Option<i64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Splits off and parses the front matter, if the page has any. Both
+/// delimiters have to be lines of their own.
+pub fn split_front_matter(content: &str) -> anyhow::Result<(FrontMatter, &str)> {
+    for (delimiter, parse) in FRONT_MATTER_FORMATS {
+        let Some(rest) = content.strip_prefix(delimiter).and_then(|rest| {
+            rest.strip_prefix('\n')
+                .or_else(|| rest.strip_prefix("\r\n"))
+        }) else {
+            continue;
+        };
+        let Some((front_matter, body)) = split_at_line(rest, delimiter) else {
+            // Without a closing line, `---` is just a thematic break.
+            if delimiter == "---" {
+                break;
+            }
+            anyhow::bail!("front matter is not closed with {delimiter}");
+        };
+        return Ok((parse(front_matter)?, body.trim_start_matches(['\r', '\n'])));
+    }
+    Ok((FrontMatter::default(), content))
+}
+
+/// Text before and after the first line that is exactly `line`.
+fn split_at_line<'a>(text: &'a str, line: &str) -> Option<(&'a str, &'a str)> {
+    let mut start = 0;
+    for current in text.split_inclusive('\n') {
+        let end = start + current.len();
+        if current.trim_end_matches(['\r', '\n']) == line {
+            return Some((&text[..start], &text[end..]));
+        }
+        start = end;
+    }
+    None
+}
+
+/// Classes of highlighted code are prefixed so they can't clash with the
+/// page's own, see `global.css`.
+const HIGHLIGHT_CLASSES: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+static SYNTAXES: Lazy<SyntaxSet> = Lazy::new(two_face::syntax::extra_newlines);
+
+/// Language of a fenced code block, its first attribute.
+fn language(info: &str) -> Option<&str> {
+    info.split([',', ' ']).find(|attr| !attr.is_empty())
+}
+
+/// Syntax for the language of a fenced code block, if it is highlighted.
+fn syntax(info: &str) -> Option<&'static SyntaxReference> {
+    let name = match language(info)? {
+        "rust" | "rs" => "Rust",
+        "toml" => "TOML",
+        "sh" | "bash" | "shell" | "console" => "Bourne Again Shell (bash)",
+        _ => return None,
+    };
+    SYNTAXES.find_syntax_by_name(name)
+}
+
+/// Code as HTML with its tokens in classed spans, or just escaped if it
+/// can't be highlighted.
+fn highlight(code: &str, syntax: Option<&SyntaxReference>) -> String {
+    let highlighted = syntax.and_then(|syntax| {
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, HIGHLIGHT_CLASSES);
+        for line in LinesWithEndings::from(code) {
+            if let Err(e) = generator.parse_html_for_line_which_includes_newline(line) {
+                tracing::warn!("failed to highlight code: {e}");
+                return None;
+            }
+        }
+        Some(generator.finalize())
+    });
+    highlighted.unwrap_or_else(|| {
+        let mut escaped = String::new();
+        escape_html(&mut escaped, code).expect("writing to a string never fails");
+        escaped
+    })
+}
+
+fn code_block(info: &str, code: &str) -> String {
+    let class = match language(info) {
+        Some(language) => {
+            let mut class = String::from(" class=\"language-");
+            escape_html(&mut class, language).expect("writing to a string never fails");
+            class.push('"');
+            class
+        }
+        None => String::new(),
+    };
+    format!(
+        "<pre><code{class}>{}</code></pre>\n",
+        highlight(code, syntax(info))
+    )
+}
+
+/// Blocks that are only meant to be read are marked as in rustdoc.
+fn is_runnable(info: &str) -> bool {
+    let mut attributes = info.split([',', ' ']).filter(|attr| !attr.is_empty());
+    attributes.next() == Some("rust")
+        && attributes.all(|attr| !matches!(attr, "ignore" | "no_run" | "compile_fail"))
+}
+
+fn runnable_block(code: &str) -> String {
+    let highlighted = highlight(code, syntax("rust"));
+    format!(
+        "<div class=\"runnable\"><pre><code class=\"language-rust\">{highlighted}</code></pre>\
+         <button class=\"run\">Run ▶️</button><pre class=\"output\" hidden></pre></div>\n"
+    )
+}
+
+/// Heading of a page, listed in its table of contents.
+#[derive(Serialize, Debug, Clone)]
+pub struct Heading {
+    pub level: u32,
+    pub title: String,
+    pub anchor: String,
+}
+
+pub struct Rendered {
+    pub html: String,
+    /// Text of the first top level heading.
+    pub title: Option<String>,
+    /// Second and third level headings, in order.
+    pub toc: Vec<Heading>,
+}
+
+/// Lowercase words joined by dashes, unique within the page.
+fn anchor(title: &str, used: &mut HashSet<String>) -> String {
+    let slug = title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug = if slug.is_empty() {
+        "section".to_owned()
+    } else {
+        slug
+    };
+
+    let mut anchor = slug.clone();
+    let mut n = 1;
+    while !used.insert(anchor.clone()) {
+        anchor = format!("{slug}-{n}");
+        n += 1;
+    }
+    anchor
+}
+
+/// Gives every heading an ID and a link to itself.
+fn anchor_headings(events: Vec<Event>) -> (Vec<Event>, Vec<Heading>) {
+    let mut anchored = Vec::with_capacity(events.len());
+    let mut headings = Vec::new();
+    let mut used = HashSet::new();
+    let mut events = events.into_iter();
+    while let Some(event) = events.next() {
+        let Event::Start(Tag::Heading(level, _, _)) = event else {
+            anchored.push(event);
+            continue;
+        };
+
+        let mut title = String::new();
+        let mut content = Vec::new();
+        for event in events.by_ref() {
+            match &event {
+                Event::End(Tag::Heading(..)) => break,
+                Event::Text(text) | Event::Code(text) => title.push_str(text),
+                _ => {}
+            }
+            content.push(event);
+        }
+
+        let anchor = anchor(&title, &mut used);
+        let start = format!("<{level} id=\"{anchor}\">");
+        let end = format!("<a class=\"anchor\" href=\"#{anchor}\">#</a></{level}>\n");
+        anchored.push(Event::Html(CowStr::from(start)));
+        anchored.extend(content);
+        anchored.push(Event::Html(CowStr::from(end)));
+        headings.push(Heading {
+            level: level as u32,
+            title,
+            anchor,
+        });
+    }
+    (anchored, headings)
+}
+
+/// Renders markdown to HTML with Rust, TOML and shell code highlighted. With
+/// `runnable`, fenced Rust code blocks get a button to run them, which the
+/// page template wires up.
+pub fn render(content: &str, runnable: bool) -> Rendered {
+    let mut events = Vec::new();
+    let mut code: Option<(CowStr, String)> = None;
+    for event in Parser::new(content) {
+        match (code.as_mut(), event) {
+            (None, Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))) => {
+                code = Some((info, String::new()));
+            }
+            (Some((_, block)), Event::Text(text)) => block.push_str(&text),
+            (Some((info, block)), Event::End(Tag::CodeBlock(_))) => {
+                let html = if runnable && is_runnable(info) {
+                    runnable_block(block)
+                } else {
+                    code_block(info, block)
+                };
+                events.push(Event::Html(CowStr::from(html)));
+                code = None;
+            }
+            (_, event) => events.push(event),
+        }
+    }
+    let (events, headings) = anchor_headings(events);
+    let title = headings
+        .iter()
+        .find(|heading| heading.level == 1)
+        .map(|heading| heading.title.clone());
+    let toc = headings
+        .into_iter()
+        .filter(|heading| matches!(heading.level, 2 | 3))
+        .collect();
+
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, events.into_iter());
+    Rendered {
+        html: html_output,
+        title,
+        toc,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_runnable_examples() -> anyhow::Result<()> {
+        let page = "+++\ntitle = \"Example\"\ntags = [\"basics\"]\n+++\n\n\
+            ```rust\nfn main() { println!(\"<hi>\"); }\n```\n\n\
+            ```rust,ignore\nnot run\n```\n\n\
+            ```toml\nkey = 1\n```\n";
+        let (front_matter, body) = split_front_matter(page)?;
+        assert_eq!(front_matter.title.as_deref(), Some("Example"));
+        assert_eq!(front_matter.tags, ["basics"]);
+
+        let html = render(body, true).html;
+        assert_eq!(html.matches("class=\"runnable\"").count(), 1);
+        assert!(html.contains("&lt;hi&gt;"));
+        assert!(html.contains("not run"));
+        assert!(!render(body, false).html.contains("runnable"));
+
+        assert!(split_front_matter("+++\ntitle = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn render_page_navigation() -> anyhow::Result<()> {
+        let page = "---\ntitle: Guide\ndescription: How it works\norder: 2\n---\n\n\
+            # Guide\n\n## Getting `started`\n\n### Details\n\n## Getting started\n";
+        let (front_matter, body) = split_front_matter(page)?;
+        assert_eq!(front_matter.title.as_deref(), Some("Guide"));
+        assert_eq!(front_matter.description.as_deref(), Some("How it works"));
+        assert_eq!(front_matter.order, Some(2));
+
+        let rendered = render(body, false);
+        let toc: Vec<_> = rendered
+            .toc
+            .iter()
+            .map(|heading| (heading.level, heading.anchor.as_str()))
+            .collect();
+        assert_eq!(
+            toc,
+            [
+                (2, "getting-started"),
+                (3, "details"),
+                (2, "getting-started-1")
+            ]
+        );
+        assert_eq!(rendered.title.as_deref(), Some("Guide"));
+        assert!(rendered.html.contains("<h1 id=\"guide\">Guide"));
+        assert!(rendered
+            .html
+            .contains("<a class=\"anchor\" href=\"#getting-started-1\">#</a></h2>"));
+        Ok(())
+    }
+
+    #[test]
+    fn highlight_code_blocks() {
+        let page = "```rust\nfn main() {}\n```\n\n\
+            ```toml\n[package]\n```\n\n\
+            ```sh\ncargo run\n```\n\n\
+            ```python\nprint(\"<hi>\")\n```\n";
+        let html = render(page, false).html;
+        assert!(html.contains("<code class=\"language-rust\">"));
+        assert!(html.contains("<span class=\"hl-storage hl-type hl-function hl-rust\">fn</span>"));
+        assert!(html.contains("<code class=\"language-toml\">"));
+        assert!(html.contains("<span class=\"hl-entity hl-name hl-table hl-toml\">package</span>"));
+        assert!(html.contains("<code class=\"language-sh\">"));
+        assert!(html.contains("<span class=\"hl-variable hl-function hl-shell\">cargo</span>"));
+        assert!(html.contains("<code class=\"language-python\">print(&quot;&lt;hi&gt;&quot;)"));
+    }
+
+    #[test]
+    fn split_front_matter_on_whole_lines() -> anyhow::Result<()> {
+        let (front_matter, body) = split_front_matter("---\r\ntitle: a\r\n---\r\n\r\nText")?;
+        assert_eq!(front_matter.title.as_deref(), Some("a"));
+        assert_eq!(body, "Text");
+
+        // Longer rules and lines merely starting with the delimiter don't close it.
+        assert_eq!(
+            split_at_line("a\n----\n---foo\n---\nb", "---"),
+            Some(("a\n----\n---foo\n", "b"))
+        );
+
+        // A page may start with a thematic break and have no front matter.
+        let page = "---\n\n# Intro\n";
+        let (front_matter, body) = split_front_matter(page)?;
+        assert!(front_matter.title.is_none());
+        assert_eq!(body, page);
+        assert_eq!(
+            split_front_matter("----\nText\n---\n")?.1,
+            "----\nText\n---\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use bytes::{Bytes, BytesMut};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use wasmtime_wasi::{OutputStream, Pollable, StdoutStream, StreamError, StreamResult};
+
+/// Raised inside the guest to stop it once it has written as much as allowed.
+#[derive(Error, Debug)]
+#[error("output limit reached")]
+pub struct OutputLimitReached;
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputKind {
+    Stdout,
+    Stderr,
+}
+
+/// Receives every chunk the guest writes, as it is written.
+pub type OutputSink = Arc<dyn Fn(OutputKind, &[u8]) + Send + Sync>;
+
+struct Buffer {
+    bytes: BytesMut,
+    capacity: usize,
+    truncated: bool,
+}
+
+impl Buffer {
+    fn remaining(&self) -> usize {
+        self.capacity - self.bytes.len()
+    }
+
+    fn truncate(&mut self) -> StreamError {
+        self.truncated = true;
+        StreamError::Trap(OutputLimitReached.into())
+    }
+}
+
+/// In-memory guest output that holds at most `capacity` bytes and traps the
+/// guest on any attempt to write more.
+#[derive(Clone)]
+pub struct BoundedOutput {
+    buffer: Arc<Mutex<Buffer>>,
+    kind: OutputKind,
+    sink: Option<OutputSink>,
+}
+
+pub struct CapturedOutput {
+    /// Invalid UTF-8 is replaced rather than rejected.
+    pub text: String,
+    pub bytes: u64,
+    pub truncated: bool,
+}
+
+impl BoundedOutput {
+    pub fn new(capacity: usize, kind: OutputKind) -> Self {
+        let buffer = Buffer {
+            bytes: BytesMut::new(),
+            capacity,
+            truncated: false,
+        };
+        Self {
+            buffer: Arc::new(Mutex::new(buffer)),
+            kind,
+            sink: None,
+        }
+    }
+
+    pub fn with_sink(mut self, sink: OutputSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    fn forward(&self, bytes: &[u8]) {
+        if let Some(sink) = &self.sink {
+            if !bytes.is_empty() {
+                sink(self.kind, bytes);
+            }
+        }
+    }
+
+    pub fn captured(&self) -> CapturedOutput {
+        let buffer = self.buffer.lock().unwrap();
+        CapturedOutput {
+            text: String::from_utf8_lossy(&buffer.bytes).into_owned(),
+            bytes: buffer.bytes.len() as u64,
+            truncated: buffer.truncated,
+        }
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl OutputStream for BoundedOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let remaining = buffer.remaining();
+        if bytes.len() > remaining {
+            buffer.bytes.extend_from_slice(&bytes[..remaining]);
+            self.forward(&bytes[..remaining]);
+            return Err(buffer.truncate());
+        }
+        buffer.bytes.extend_from_slice(&bytes);
+        self.forward(&bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        let mut buffer = self.buffer.lock().unwrap();
+        match buffer.remaining() {
+            // Stop here since the guest would otherwise wait for space forever.
+            0 => Err(buffer.truncate()),
+            remaining => Ok(remaining),
+        }
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for BoundedOutput {
+    async fn ready(&mut self) {}
+}
+
+impl StdoutStream for BoundedOutput {
+    fn stream(&self) -> Box<dyn OutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
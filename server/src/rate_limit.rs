@@ -0,0 +1,341 @@
+use crate::error::Rejection;
+use crate::scheduler::ClientId;
+use crate::State;
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+const FLY_CLIENT_IP_HEADER: &str = "fly-client-ip";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+const API_KEY_HEADER: &str = "x-api-key";
+/// Buckets are only pruned once there are this many of them.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+/// Pruning goes over every bucket, so it isn't done more often than this.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_burst() -> u32 {
+    10
+}
+
+fn default_per_minute() -> u32 {
+    30
+}
+
+fn default_api_key_burst() -> u32 {
+    50
+}
+
+fn default_api_key_per_minute() -> u32 {
+    300
+}
+
+/// Read from `RATE_LIMIT_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Requests a client may make at once before being limited.
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Rate at which a client's allowance refills.
+    #[serde(default = "default_per_minute")]
+    pub per_minute: u32,
+    /// Comma-separated keys sent in the `X-Api-Key` header to get the
+    /// `api_key_*` quotas instead.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    #[serde(default = "default_api_key_burst")]
+    pub api_key_burst: u32,
+    #[serde(default = "default_api_key_per_minute")]
+    pub api_key_per_minute: u32,
+    /// Take the client address from `Fly-Client-IP` or `X-Forwarded-For`.
+    /// Only safe behind a proxy that sets these headers.
+    #[serde(default)]
+    pub trust_proxy_headers: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            burst: default_burst(),
+            per_minute: default_per_minute(),
+            api_keys: vec![],
+            api_key_burst: default_api_key_burst(),
+            api_key_per_minute: default_api_key_per_minute(),
+            trust_proxy_headers: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    burst: u32,
+    per_minute: u32,
+}
+
+impl Quota {
+    fn refill_interval(self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.max(1)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    quota: Quota,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        let refilled = elapsed.as_secs_f64() / self.quota.refill_interval().as_secs_f64();
+        (self.tokens + refilled).min(self.quota.burst as f64)
+    }
+}
+
+struct Buckets {
+    by_client: HashMap<ClientId, Bucket>,
+    pruned: Instant,
+}
+
+/// Token buckets per client, keyed by API key or address.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    api_keys: HashSet<String>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            api_keys: config.api_keys.iter().cloned().collect(),
+            config,
+            buckets: Mutex::new(Buckets {
+                by_client: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        if self.config.trust_proxy_headers {
+            let fly_client_ip = headers
+                .get(FLY_CLIENT_IP_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok());
+            // Proxies append the address they saw, so only the last entry is trusted.
+            let forwarded_for = || {
+                headers
+                    .get_all(FORWARDED_FOR_HEADER)
+                    .iter()
+                    .next_back()
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.rsplit(',').next())
+                    .and_then(|value| value.trim().parse().ok())
+            };
+            if let Some(ip) = fly_client_ip.or_else(forwarded_for) {
+                return Some(ip);
+            }
+        }
+        peer
+    }
+
+    /// Works out who sent the request and their quota. Unknown API keys are rejected.
+    pub fn identify(
+        &self,
+        headers: &HeaderMap,
+        peer: Option<IpAddr>,
+    ) -> Result<(ClientId, Quota), Rejection> {
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            let key = key.to_str().unwrap_or_default();
+            if !self.api_keys.contains(key) {
+                return Err(Rejection {
+                    status: StatusCode::UNAUTHORIZED,
+                    error: "invalid_api_key",
+                    message: "The API key is not valid".into(),
+                    retry_after: None,
+                });
+            }
+            let quota = Quota {
+                burst: self.config.api_key_burst,
+                per_minute: self.config.api_key_per_minute,
+            };
+            return Ok((ClientId::api_key(key), quota));
+        }
+
+        let ip = self
+            .client_ip(headers, peer)
+            .unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let quota = Quota {
+            burst: self.config.burst,
+            per_minute: self.config.per_minute,
+        };
+        Ok((ClientId::from(ip), quota))
+    }
+
+    /// Takes a token from the client's bucket.
+    pub fn check(&self, client: &ClientId, quota: Quota) -> Result<(), Rejection> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_client.len() >= MAX_TRACKED_CLIENTS
+            && now.duration_since(buckets.pruned) >= PRUNE_INTERVAL
+        {
+            // Full buckets are the same as no bucket at all.
+            buckets
+                .by_client
+                .retain(|_, bucket| bucket.tokens_at(now) < bucket.quota.burst as f64);
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.by_client.entry(client.clone()).or_insert(Bucket {
+            tokens: quota.burst as f64,
+            updated: now,
+            quota,
+        });
+        bucket.tokens = bucket.tokens_at(now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = (1.0 - bucket.tokens) * quota.refill_interval().as_secs_f64();
+        let retry_after = wait.ceil() as u64;
+        Err(Rejection {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            error: "rate_limited",
+            message: format!("Too many requests, try again in {retry_after} seconds"),
+            retry_after: Some(retry_after),
+        })
+    }
+}
+
+/// Identifies the client for the handlers and rejects it once its quota is used up.
+pub async fn enforce<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let state = req
+        .extensions()
+        .get::<Arc<State>>()
+        .cloned()
+        .expect("state extension is missing");
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let limiter = &state.rate_limiter;
+
+    let (client, quota) = match limiter.identify(req.headers(), peer) {
+        Ok(identified) => identified,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if limiter.config.enabled {
+        if let Err(rejection) = limiter.check(&client, quota) {
+            tracing::info!(retry_after = ?rejection.retry_after, "rate limited client");
+            return rejection.into_response();
+        }
+    }
+
+    req.extensions_mut().insert(client);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[tokio::test]
+    async fn rate_limit_clients_separately() -> anyhow::Result<()> {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            burst: 2,
+            per_minute: 1,
+            api_keys: vec!["secret".into()],
+            api_key_burst: 3,
+            trust_proxy_headers: true,
+            ..RateLimitConfig::default()
+        });
+        let peer = Some(IpAddr::from([10, 0, 0, 1]));
+        let allowed = |headers: &HeaderMap| {
+            let (client, quota) = limiter.identify(headers, peer).ok()?;
+            Some(limiter.check(&client, quota))
+        };
+
+        let mut forwarded = HeaderMap::new();
+        forwarded.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2"),
+        );
+        assert!(matches!(allowed(&forwarded), Some(Ok(()))));
+        assert!(matches!(allowed(&forwarded), Some(Ok(()))));
+        assert!(matches!(
+            allowed(&forwarded),
+            Some(Err(rejection))
+                if rejection.status == StatusCode::TOO_MANY_REQUESTS
+                    && rejection.retry_after == Some(60)
+        ));
+
+        // Only the last forwarded address is trusted, so this one wasn't limited.
+        let mut fly = HeaderMap::new();
+        fly.insert("fly-client-ip", HeaderValue::from_static("1.1.1.1"));
+        assert!(matches!(allowed(&fly), Some(Ok(()))));
+
+        let mut keyed = HeaderMap::new();
+        keyed.insert("x-api-key", HeaderValue::from_static("secret"));
+        for _ in 0..3 {
+            assert!(matches!(allowed(&keyed), Some(Ok(()))));
+        }
+        assert!(matches!(allowed(&keyed), Some(Err(_))));
+
+        keyed.insert("x-api-key", HeaderValue::from_static("guess"));
+        assert!(matches!(
+            limiter.identify(&keyed, peer),
+            Err(rejection) if rejection.status == StatusCode::UNAUTHORIZED
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_full_buckets_now_and_then() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            burst: 1,
+            per_minute: 60_000,
+            ..RateLimitConfig::default()
+        });
+        let quota = Quota {
+            burst: 1,
+            per_minute: 60_000,
+        };
+        let track_clients = || {
+            for i in 0..MAX_TRACKED_CLIENTS as u32 {
+                let client = ClientId::from(IpAddr::from(i.to_be_bytes()));
+                limiter.check(&client, quota).unwrap();
+            }
+        };
+        let tracked = || limiter.buckets.lock().unwrap().by_client.len();
+        let newcomer = ClientId::from(IpAddr::from([255, 255, 255, 255]));
+
+        track_clients();
+        // Every bucket refills within a millisecond.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        limiter.check(&newcomer, quota).unwrap();
+        assert_eq!(tracked(), MAX_TRACKED_CLIENTS + 1);
+
+        limiter.buckets.lock().unwrap().pruned -= PRUNE_INTERVAL;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        limiter.check(&newcomer, quota).unwrap();
+        assert_eq!(tracked(), 1);
+    }
+}
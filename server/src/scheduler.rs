@@ -0,0 +1,366 @@
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::{oneshot, watch};
+
+fn default_compile_slots() -> usize {
+    std::thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1)
+}
+
+fn default_execute_slots() -> usize {
+    64
+}
+
+fn default_max_queue() -> usize {
+    256
+}
+
+fn default_retry_after() -> u64 {
+    5
+}
+
+/// Read from `SCHEDULER_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct SchedulerConfig {
+    /// Number of rustc processes that may run at the same time.
+    #[serde(default = "default_compile_slots")]
+    pub compile_slots: usize,
+    /// Number of guests that may run at the same time. Should not exceed
+    /// `ENGINE_MAX_INSTANCES` when pooling is enabled.
+    #[serde(default = "default_execute_slots")]
+    pub execute_slots: usize,
+    /// Jobs that may wait for each kind of slot before new ones are rejected.
+    #[serde(default = "default_max_queue")]
+    pub max_queue: usize,
+    /// Seconds clients are asked to wait before retrying a rejected job.
+    #[serde(default = "default_retry_after")]
+    pub retry_after: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            compile_slots: default_compile_slots(),
+            execute_slots: default_execute_slots(),
+            max_queue: default_max_queue(),
+            retry_after: default_retry_after(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("job queue is full")]
+pub struct QueueFull;
+
+/// Whoever submitted a job. Jobs of different clients are served in turn.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientId(String);
+
+impl ClientId {
+    pub fn api_key(key: &str) -> Self {
+        Self(format!("key:{key}"))
+    }
+}
+
+impl From<IpAddr> for ClientId {
+    fn from(ip: IpAddr) -> Self {
+        Self(ip.to_string())
+    }
+}
+
+struct Waiter {
+    id: u64,
+    grant: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Queue {
+    available: usize,
+    /// Clients with waiting jobs, in the order they are served next.
+    rotation: VecDeque<ClientId>,
+    waiting: HashMap<ClientId, VecDeque<Waiter>>,
+    len: usize,
+    next_id: u64,
+}
+
+impl Queue {
+    fn push(&mut self, client: &ClientId, grant: oneshot::Sender<()>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let waiters = self.waiting.entry(client.clone()).or_default();
+        if waiters.is_empty() {
+            self.rotation.push_back(client.clone());
+        }
+        waiters.push_back(Waiter { id, grant });
+        self.len += 1;
+        id
+    }
+
+    /// Takes the first job of the next client in turn.
+    fn pop(&mut self) -> Option<Waiter> {
+        let client = self.rotation.pop_front()?;
+        let waiters = self.waiting.get_mut(&client)?;
+        let waiter = waiters.pop_front()?;
+        if waiters.is_empty() {
+            self.waiting.remove(&client);
+        } else {
+            self.rotation.push_back(client);
+        }
+        self.len -= 1;
+        Some(waiter)
+    }
+
+    fn remove(&mut self, client: &ClientId, id: u64) -> bool {
+        let Some(waiters) = self.waiting.get_mut(client) else {
+            return false;
+        };
+        let Some(index) = waiters.iter().position(|waiter| waiter.id == id) else {
+            return false;
+        };
+        waiters.remove(index);
+        if waiters.is_empty() {
+            self.waiting.remove(client);
+            self.rotation.retain(|other| other != client);
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// 1-based position in the order jobs would be served if nothing else
+    /// was queued in the meantime.
+    fn position(&self, client: &ClientId, id: u64) -> Option<usize> {
+        let depth = self
+            .waiting
+            .get(client)?
+            .iter()
+            .position(|waiter| waiter.id == id)?;
+        // Clients ahead in the rotation get one more turn before this job.
+        let mut ahead = true;
+        let mut position = depth + 1;
+        for other in &self.rotation {
+            if other == client {
+                ahead = false;
+                continue;
+            }
+            let turns = if ahead { depth + 1 } else { depth };
+            position += self.waiting[other].len().min(turns);
+        }
+        Some(position)
+    }
+}
+
+/// A fixed number of slots, handed out in turn to the clients waiting.
+struct Pool {
+    queue: Mutex<Queue>,
+    max_queue: usize,
+    /// Notified whenever queue positions may have changed.
+    moved: watch::Sender<()>,
+}
+
+impl Pool {
+    fn new(slots: usize, max_queue: usize) -> Arc<Self> {
+        let queue = Queue {
+            available: slots,
+            ..Queue::default()
+        };
+        Arc::new(Self {
+            queue: Mutex::new(queue),
+            max_queue,
+            moved: watch::Sender::new(()),
+        })
+    }
+
+    async fn acquire(
+        self: &Arc<Self>,
+        client: &ClientId,
+        mut on_queued: impl FnMut(usize),
+    ) -> Result<Permit, QueueFull> {
+        let (grant, receiver) = oneshot::channel();
+        let id = {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.available > 0 && queue.len == 0 {
+                queue.available -= 1;
+                return Ok(Permit { pool: self.clone() });
+            }
+            if queue.len >= self.max_queue {
+                return Err(QueueFull);
+            }
+            queue.push(client, grant)
+        };
+        self.moved.send_replace(());
+
+        let mut waiting = Waiting {
+            pool: self.clone(),
+            client: client.clone(),
+            id,
+            receiver,
+            granted: false,
+        };
+        let mut moved = self.moved.subscribe();
+        let mut reported = None;
+        loop {
+            let position = self.queue.lock().unwrap().position(client, id);
+            if let Some(position) = position.filter(|&position| reported != Some(position)) {
+                reported = Some(position);
+                on_queued(position);
+            }
+
+            tokio::select! {
+                _ = &mut waiting.receiver => {
+                    waiting.granted = true;
+                    return Ok(Permit { pool: self.clone() });
+                }
+                _ = moved.changed() => {}
+            }
+        }
+    }
+
+    /// Hands the slot to the next job in turn or makes it available.
+    fn release(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        let mut handed_over = false;
+        while let Some(waiter) = queue.pop() {
+            // Jobs that were dropped while queued don't take the slot.
+            if waiter.grant.send(()).is_ok() {
+                handed_over = true;
+                break;
+            }
+        }
+        if !handed_over {
+            queue.available += 1;
+        }
+        drop(queue);
+        self.moved.send_replace(());
+    }
+}
+
+/// Removes a job from the queue if it is dropped while waiting.
+struct Waiting {
+    pool: Arc<Pool>,
+    client: ClientId,
+    id: u64,
+    receiver: oneshot::Receiver<()>,
+    granted: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        let removed = self
+            .pool
+            .queue
+            .lock()
+            .unwrap()
+            .remove(&self.client, self.id);
+        if removed {
+            self.pool.moved.send_replace(());
+        } else if self.receiver.try_recv().is_ok() {
+            // The slot was granted just before the job was dropped.
+            self.pool.release();
+        }
+    }
+}
+
+/// Holds a slot until dropped.
+pub struct Permit {
+    pool: Arc<Pool>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.pool.release();
+    }
+}
+
+/// Bounds how many compilations and executions happen at once. Jobs beyond
+/// that wait in a bounded queue, taking turns between clients.
+pub struct Scheduler {
+    compile: Arc<Pool>,
+    execute: Arc<Pool>,
+    retry_after: u64,
+}
+
+impl Scheduler {
+    pub fn new(config: &SchedulerConfig) -> Self {
+        Self {
+            compile: Pool::new(config.compile_slots, config.max_queue),
+            execute: Pool::new(config.execute_slots, config.max_queue),
+            retry_after: config.retry_after,
+        }
+    }
+
+    /// Waits for a compile slot, calling `on_queued` with the job's position
+    /// in the queue whenever it changes.
+    pub async fn compile_slot(
+        &self,
+        client: &ClientId,
+        on_queued: impl FnMut(usize),
+    ) -> Result<Permit, QueueFull> {
+        self.compile.acquire(client, on_queued).await
+    }
+
+    /// Like [`Scheduler::compile_slot`] but for running a guest.
+    pub async fn execute_slot(
+        &self,
+        client: &ClientId,
+        on_queued: impl FnMut(usize),
+    ) -> Result<Permit, QueueFull> {
+        self.execute.acquire(client, on_queued).await
+    }
+
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queue_jobs_fairly_across_clients() -> anyhow::Result<()> {
+        let scheduler = Arc::new(Scheduler::new(&SchedulerConfig {
+            compile_slots: 1,
+            max_queue: 3,
+            ..SchedulerConfig::default()
+        }));
+        let first = ClientId::from(IpAddr::from([10, 0, 0, 1]));
+        let second = ClientId::from(IpAddr::from([10, 0, 0, 2]));
+
+        let held = scheduler.compile_slot(&first, |_| {}).await?;
+        let served = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut jobs = vec![];
+        for (name, client) in [("a1", &first), ("a2", &first), ("b1", &second)] {
+            let (scheduler, served, client) = (scheduler.clone(), served.clone(), client.clone());
+            jobs.push(tokio::spawn(async move {
+                let mut positions = vec![];
+                let _permit = scheduler
+                    .compile_slot(&client, |position| positions.push(position))
+                    .await?;
+                served.lock().unwrap().push(name);
+                anyhow::Ok(positions)
+            }));
+            tokio::task::yield_now().await;
+        }
+
+        let rejected = scheduler.compile_slot(&second, |_| {}).await;
+        assert!(matches!(rejected, Err(QueueFull)));
+
+        drop(held);
+        let mut first_positions = vec![];
+        for job in jobs {
+            first_positions.push(job.await??[0]);
+        }
+        assert_eq!(*served.lock().unwrap(), ["a1", "b1", "a2"]);
+        // The second client's job skipped ahead of the first client's second one.
+        assert_eq!(first_positions, [1, 2, 2]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use crate::handler::{BuildOptions, HandlerResponse};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// Shortest ID handed out. IDs get longer only when two snippets share a prefix.
+const ID_LENGTH: usize = 10;
+/// Revisions returned by [`SnippetStore::history`] at most.
+const MAX_HISTORY: usize = 100;
+
+fn default_dir() -> PathBuf {
+    PathBuf::from("snippets")
+}
+
+/// Read from `SNIPPETS_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct SnippetsConfig {
+    #[serde(default = "default_dir")]
+    pub dir: PathBuf,
+}
+
+impl Default for SnippetsConfig {
+    fn default() -> Self {
+        Self { dir: default_dir() }
+    }
+}
+
+/// Code shared by a user together with the options to build it with. Every
+/// save is a new revision, so a snippet never changes once stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub code: String,
+    #[serde(default)]
+    pub options: BuildOptions,
+    /// Revision this one was saved on top of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Snippet this lineage was forked from. Forks have no parent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forked_from: Option<String>,
+}
+
+/// Where a new revision goes, read from the query string when saving.
+#[derive(Deserialize, Debug, Default)]
+pub struct RevisionOptions {
+    /// Continues the lineage of this revision.
+    pub parent: Option<String>,
+    /// Starts a new lineage from this revision.
+    pub fork: Option<String>,
+}
+
+/// Read from the query string when running a stored snippet.
+#[derive(Deserialize, Debug, Default)]
+pub struct SnippetRunOptions {
+    /// Keep the result so the snippet shows it when opened, unless it
+    /// already has one or the run failed for reasons other than the code,
+    /// like a timeout.
+    #[serde(default)]
+    pub save: bool,
+}
+
+/// Result of running a snippet, kept next to it so the snippet can show it
+/// without being run. Only the first saved result is kept.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredResult {
+    pub toolchain: String,
+    pub response: HandlerResponse,
+}
+
+/// A revision in a snippet's history.
+#[derive(Serialize, Debug)]
+pub struct Revision {
+    pub id: String,
+    #[serde(flatten)]
+    pub snippet: Snippet,
+    /// Unified diff of the code against the parent revision.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+/// Snippets stored as JSON files named after a prefix of their hash. Saving
+/// the same snippet twice gives the same ID.
+pub struct SnippetStore {
+    dir: PathBuf,
+}
+
+impl SnippetStore {
+    pub fn new(config: SnippetsConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        Ok(Self { dir: config.dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn result_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.result.json"))
+    }
+
+    /// Stores the snippet unless it already exists and returns its ID.
+    pub async fn put(&self, snippet: &Snippet) -> anyhow::Result<String> {
+        let contents = serde_json::to_vec(snippet)?;
+        let hash = hex::encode(Sha256::digest(&contents));
+
+        for len in ID_LENGTH..=hash.len() {
+            let id = &hash[..len];
+            let path = self.path(id);
+            match fs::read(&path).await {
+                Ok(existing) if existing == contents => return Ok(id.to_owned()),
+                // Another snippet has the same prefix, so try a longer one.
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let staging = tempfile::Builder::new()
+                .prefix(".staging-")
+                .tempfile_in(&self.dir)?;
+            fs::write(staging.path(), &contents).await?;
+            // Never replaces an existing file, so stored snippets can't change.
+            match staging.persist_noclobber(&path) {
+                Ok(_) => return Ok(id.to_owned()),
+                Err(e) if e.error.kind() == ErrorKind::AlreadyExists => {
+                    // Stored concurrently, possibly by a request for the same snippet.
+                    if fs::read(&path).await? == contents {
+                        return Ok(id.to_owned());
+                    }
+                }
+                Err(e) => return Err(e.error.into()),
+            }
+        }
+        anyhow::bail!("no free ID left for snippet {hash}")
+    }
+
+    /// `None` if there is no snippet with this ID.
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<Snippet>> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        match fs::read(self.path(id)).await {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores the result of the first run only, like the snippet itself it
+    /// never changes afterwards. `false` if the snippet already had one.
+    pub async fn put_result(&self, id: &str, result: &StoredResult) -> anyhow::Result<bool> {
+        let staging = tempfile::Builder::new()
+            .prefix(".staging-")
+            .tempfile_in(&self.dir)?;
+        fs::write(staging.path(), serde_json::to_vec(result)?).await?;
+        // Linking is atomic, so readers never see a partially written result.
+        match staging.persist_noclobber(self.result_path(id)) {
+            Ok(_) => Ok(true),
+            Err(e) if e.error.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e.error.into()),
+        }
+    }
+
+    pub async fn get_result(&self, id: &str) -> anyhow::Result<Option<StoredResult>> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        match fs::read(self.result_path(id)).await {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Revisions from `id` back to the start of its lineage, newest first.
+    /// Only the last [`MAX_HISTORY`] revisions are returned.
+    pub async fn history(&self, id: &str) -> anyhow::Result<Option<Vec<Revision>>> {
+        let Some(snippet) = self.get(id).await? else {
+            return Ok(None);
+        };
+
+        let mut revisions = vec![(id.to_owned(), snippet)];
+        while revisions.len() < MAX_HISTORY {
+            let Some(parent) = revisions
+                .last()
+                .and_then(|(_, snippet)| snippet.parent.clone())
+            else {
+                break;
+            };
+            match self.get(&parent).await? {
+                Some(snippet) => revisions.push((parent, snippet)),
+                None => break,
+            }
+        }
+
+        let diffs: Vec<_> = revisions
+            .iter()
+            .enumerate()
+            .map(|(i, (id, snippet))| {
+                revisions.get(i + 1).map(|(parent_id, parent)| {
+                    TextDiff::from_lines(&parent.code, &snippet.code)
+                        .unified_diff()
+                        .header(parent_id, id)
+                        .to_string()
+                })
+            })
+            .collect();
+        let history = revisions
+            .into_iter()
+            .zip(diffs)
+            .map(|((id, snippet), diff)| Revision { id, snippet, diff })
+            .collect();
+        Ok(Some(history))
+    }
+}
+
+/// Also keeps IDs from escaping the store's directory.
+fn is_valid_id(id: &str) -> bool {
+    (ID_LENGTH..=64).contains(&id.len())
+        && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::CrateType;
+
+    fn store() -> anyhow::Result<SnippetStore> {
+        Ok(SnippetStore::new(SnippetsConfig {
+            dir: tempfile::tempdir()?.into_path(),
+        })?)
+    }
+
+    fn snippet(code: &str) -> Snippet {
+        Snippet {
+            code: code.into(),
+            options: BuildOptions::default(),
+            parent: None,
+            forked_from: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn store_and_load_snippets() -> anyhow::Result<()> {
+        let store = store()?;
+        let snippet = Snippet {
+            options: BuildOptions {
+                crate_type: CrateType::Lib,
+            },
+            ..snippet("fn main() { println!(\"shared\"); }")
+        };
+        let id = store.put(&snippet).await?;
+        assert_eq!(store.put(&snippet).await?, id);
+        assert_eq!(store.get(&id).await?, Some(snippet.clone()));
+
+        let other = Snippet {
+            options: BuildOptions::default(),
+            ..snippet
+        };
+        assert_ne!(store.put(&other).await?, id);
+
+        assert_eq!(store.get("0000000000").await?, None);
+        assert_eq!(store.get("../../etc/passwd").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_snippet_revisions() -> anyhow::Result<()> {
+        let store = store()?;
+        let save = |code: &str, parent: Option<&str>, forked_from: Option<&str>| {
+            let snippet = Snippet {
+                parent: parent.map(Into::into),
+                forked_from: forked_from.map(Into::into),
+                ..snippet(code)
+            };
+            let store = &store;
+            async move { store.put(&snippet).await }
+        };
+
+        let first = save("fn main() {}\n", None, None).await?;
+        let second = save("fn main() {\n    println!(\"2\");\n}\n", Some(&first), None).await?;
+        let third = save(
+            "fn main() {\n    println!(\"3\");\n}\n",
+            Some(&second),
+            None,
+        )
+        .await?;
+        assert_eq!(store.get(&first).await?.unwrap().code, "fn main() {}\n");
+
+        let history = store.history(&third).await?.unwrap();
+        let ids: Vec<_> = history
+            .iter()
+            .map(|revision| revision.id.as_str())
+            .collect();
+        assert_eq!(ids, [third.as_str(), second.as_str(), first.as_str()]);
+        let diff = history[0].diff.as_deref().unwrap();
+        assert!(diff.contains("-    println!(\"2\");"));
+        assert!(diff.contains("+    println!(\"3\");"));
+        assert!(history[2].diff.is_none());
+
+        let fork = save("fn main() {}\n", None, Some(&second)).await?;
+        assert_ne!(fork, first);
+        let history = store.history(&fork).await?.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(
+            history[0].snippet.forked_from.as_deref(),
+            Some(second.as_str())
+        );
+
+        Ok(())
+    }
+}
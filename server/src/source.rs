@@ -0,0 +1,156 @@
+use crate::error::Rejection;
+use crate::limits::LimitsConfig;
+use crate::State;
+use axum::{
+    async_trait,
+    body::HttpBody,
+    extract::{FromRequest, RequestParts},
+    http::{header, StatusCode},
+    BoxError,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::sync::Arc;
+
+fn too_large(message: String) -> Rejection {
+    Rejection {
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        error: "source_too_large",
+        message,
+        retry_after: None,
+    }
+}
+
+fn bad_request(message: &str) -> Rejection {
+    Rejection {
+        status: StatusCode::BAD_REQUEST,
+        error: "invalid_source",
+        message: message.into(),
+        retry_after: None,
+    }
+}
+
+/// Source code sent as the request body. Bodies over the configured limits
+/// are rejected while being read, so they never reach the disk.
+pub struct SourceCode(pub String);
+
+impl SourceCode {
+    pub fn check(limits: &LimitsConfig, code: String) -> Result<Self, Rejection> {
+        limits.check_source(&code).map_err(too_large)?;
+        Ok(Self(code))
+    }
+}
+
+/// Reads the whole body, rejecting it as soon as it exceeds `max_request_size`.
+async fn read_body<B>(req: &mut RequestParts<B>) -> Result<(Arc<State>, Bytes), Rejection>
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let state = req
+        .extensions()
+        .get::<Arc<State>>()
+        .cloned()
+        .expect("state extension is missing");
+    let max_size = state.limits.max_request_size;
+    let request_too_large = || {
+        too_large(format!(
+            "request body exceeds the maximum of {max_size} bytes"
+        ))
+    };
+
+    let declared_size = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared_size.is_some_and(|size| size > max_size) {
+        return Err(request_too_large());
+    }
+
+    let body = req
+        .take_body()
+        .ok_or_else(|| bad_request("request body was already read"))?;
+    tokio::pin!(body);
+    let mut bytes = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| bad_request("failed to read request body"))?;
+        if bytes.len() + chunk.remaining() > max_size {
+            return Err(request_too_large());
+        }
+        bytes.put(chunk);
+    }
+    Ok((state, bytes.freeze()))
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for SourceCode
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let (state, bytes) = read_body(req).await?;
+        let code = String::from_utf8(bytes.to_vec())
+            .map_err(|_| bad_request("source code is not valid UTF-8"))?;
+        Self::check(&state.limits, code)
+    }
+}
+
+/// Binary request body, such as an uploaded archive, under the same size
+/// limit as source code sent as is.
+pub struct RawBody(pub Bytes);
+
+#[async_trait]
+impl<B> FromRequest<B> for RawBody
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let (_, bytes) = read_body(req).await?;
+        Ok(Self(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::tests::test_state;
+    use crate::wasm::EngineConfig;
+    use axum::body::Body;
+    use axum::http::Request;
+
+    #[tokio::test]
+    async fn reject_oversized_source() -> anyhow::Result<()> {
+        let state = test_state(EngineConfig::default(), None);
+        let extract = |size: usize| {
+            let request = Request::builder()
+                .extension(state.clone())
+                .body(Body::from("a".repeat(size)));
+            async move {
+                let result = SourceCode::from_request(&mut RequestParts::new(request?)).await;
+                anyhow::Ok(result.err().map(|rejection| rejection.status))
+            }
+        };
+
+        let limits = &state.limits;
+        assert_eq!(extract(limits.max_source_size).await?, None);
+        assert_eq!(
+            extract(limits.max_source_size + 1).await?,
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(
+            extract(limits.max_request_size + 1).await?,
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+
+        Ok(())
+    }
+}
@@ -1,28 +1,92 @@
+use crate::handler::HandlerResponse;
+use crate::markdown::{self, FrontMatter};
+use crate::snippets::{Snippet, StoredResult};
+use anyhow::Context as _;
 use axum::body::{self, Full};
 use axum::handler::Handler;
 use axum::http::header::HeaderName;
-use axum::http::{header, HeaderValue, Response, StatusCode, Uri};
+use axum::http::{header, HeaderMap, HeaderValue, Response, StatusCode, Uri};
 use axum::response::IntoResponse;
 use axum::routing::{get, MethodRouter};
 use bytes::Bytes;
 use http_body::combinators::UnsyncBoxBody;
 use include_dir::include_dir;
-use once_cell::sync::Lazy;
-use pulldown_cmark::{html, Parser};
-use std::path::Path;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tera::{Context, Tera};
 use tower_http::set_header::SetResponseHeaderLayer;
 
 pub const CDN_CACHE_CONTROL_HEADER: HeaderName = HeaderName::from_static("cdn-cache-control");
+/// Lets any site frame the page, see [`embed_page`].
+const ANY_FRAME_ANCESTOR: HeaderValue = HeaderValue::from_static("frame-ancestors *");
+const EXAMPLES_DIR: &str = "examples";
+const MIN_EMBED_HEIGHT: u32 = 120;
+const MAX_EMBED_HEIGHT: u32 = 2000;
 
 static STATIC_DIR: include_dir::Dir = include_dir!("public");
-static TERA: Lazy<Tera> = Lazy::new(|| match Tera::new("templates/**/*") {
-    Ok(t) => t,
-    Err(e) => {
-        println!("Parsing error(s): {}", e);
-        ::std::process::exit(1);
+/// Set up by [`init`] before the server starts.
+static PAGES: OnceCell<Pages> = OnceCell::new();
+
+fn default_templates() -> String {
+    "templates/**/*".to_owned()
+}
+
+fn default_pages_dir() -> PathBuf {
+    PathBuf::from("md")
+}
+
+/// Read from `PAGES_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct PagesConfig {
+    /// Glob matching the Tera templates.
+    #[serde(default = "default_templates")]
+    pub templates: String,
+    /// Render the pages again from the templates and the markdown in `dir` on
+    /// every request, so that edits show up without a restart.
+    #[serde(default)]
+    pub reload: bool,
+    /// Markdown sources, only read with `reload`. Otherwise the pages
+    /// compiled in from `public/` are served.
+    #[serde(default = "default_pages_dir")]
+    pub dir: PathBuf,
+}
+
+impl Default for PagesConfig {
+    fn default() -> Self {
+        Self {
+            templates: default_templates(),
+            reload: false,
+            dir: default_pages_dir(),
+        }
+    }
+}
+
+struct Pages {
+    config: PagesConfig,
+    site: Arc<Site>,
+}
+
+/// Parses the templates and renders every markdown page, so that mistakes in
+/// either stop the server from starting instead of failing requests.
+pub fn init(config: PagesConfig) -> anyhow::Result<()> {
+    let site = Arc::new(Site::load(&config)?);
+    PAGES
+        .set(Pages { config, site })
+        .map_err(|_| anyhow::anyhow!("pages are already initialized"))
+}
+
+/// The site rendered by [`init`], or rendered afresh when reloading.
+fn site() -> anyhow::Result<Arc<Site>> {
+    let pages = PAGES.get().context("pages are not initialized")?;
+    if pages.config.reload {
+        return Ok(Arc::new(Site::load(&pages.config)?));
     }
-});
+    Ok(pages.site.clone())
+}
 
 fn mime_type_from_path<P>(path: P) -> mime_guess::Mime
 where
@@ -54,21 +118,326 @@ fn file_to_response(
     )
 }
 
-fn convert_md_to_html(content: &str) -> Vec<u8> {
-    let parser = Parser::new(content);
-    let mut html_output = String::new();
-    html::push_html(&mut html_output, parser);
+fn internal_error(error: anyhow::Error) -> Response<UnsyncBoxBody<Bytes, axum::Error>> {
+    tracing::error!("{error:#}");
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
 
-    let mut context = Context::new();
-    context.insert("html", &html_output);
+/// Rendered HTML with a strong ETag derived from its contents.
+struct Page {
+    html: Bytes,
+    etag: HeaderValue,
+}
+
+impl Page {
+    fn new(html: impl Into<Bytes>) -> Self {
+        let html = html.into();
+        let etag = format!("\"{}\"", hex::encode(Sha256::digest(&html)));
+        Self {
+            html,
+            etag: HeaderValue::from_str(&etag).expect("hex is a valid header value"),
+        }
+    }
+
+    /// The page, or 304 if the client's copy is the same.
+    fn to_response(&self, headers: &HeaderMap) -> Response<UnsyncBoxBody<Bytes, axum::Error>> {
+        let unchanged = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|tags| tags.to_str().ok())
+            .is_some_and(|tags| {
+                tags.split(',').map(str::trim).any(|tag| {
+                    tag == "*" || tag.trim_start_matches("W/").as_bytes() == self.etag.as_bytes()
+                })
+            });
+        let mut response = if unchanged {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            bytes_to_response(
+                Full::from(self.html.clone()),
+                mime_guess::mime::TEXT_HTML_UTF_8,
+            )
+        };
+        response
+            .headers_mut()
+            .insert(header::ETAG, self.etag.clone());
+        response
+    }
+}
+
+/// Path of a markdown page without the extension, as in its URL.
+fn page_path(file: &Path) -> String {
+    let path = file.with_extension("");
+    let components: Vec<_> = path.iter().map(|part| part.to_string_lossy()).collect();
+    components.join("/")
+}
+
+/// Markdown files compiled in from `public/` by their page path.
+fn embedded_markdown(dir: &'static include_dir::Dir, sources: &mut Vec<(String, String)>) {
+    for file in dir.files() {
+        if file.path().extension() != Some("md".as_ref()) {
+            continue;
+        }
+        let content = file.contents_utf8().unwrap_or_default();
+        sources.push((page_path(file.path()), content.to_owned()));
+    }
+    for dir in dir.dirs() {
+        embedded_markdown(dir, sources);
+    }
+}
+
+/// Markdown files under `dir` on disk by their page path relative to `root`.
+fn markdown_on_disk(
+    root: &Path,
+    dir: &Path,
+    sources: &mut Vec<(String, String)>,
+) -> anyhow::Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("failed to read {dir:?}"))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            markdown_on_disk(root, &path, sources)?;
+        } else if path.extension() == Some("md".as_ref()) {
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {path:?}"))?;
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            sources.push((page_path(relative), content));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct NavLink {
+    path: String,
+    title: String,
+}
+
+#[derive(Serialize, Clone)]
+struct ExampleLink {
+    path: String,
+    title: String,
+    tags: Vec<String>,
+}
+
+/// Link to the examples with a tag, which may contain characters that
+/// aren't allowed in a query string as is.
+#[derive(Serialize)]
+struct TagLink {
+    name: String,
+    href: String,
+}
+
+impl TagLink {
+    fn new(name: &str) -> Self {
+        let tag: String = url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+        Self {
+            name: name.to_owned(),
+            href: format!("/{EXAMPLES_DIR}?tag={tag}"),
+        }
+    }
+}
+
+/// First `tag` in the query string of the examples index. Parsed by hand so
+/// other pages and assets ignore their query strings, whatever they contain.
+fn selected_tag(query: Option<&str>) -> Option<String> {
+    url::form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == "tag")
+        .map(|(_, tag)| tag.into_owned())
+}
 
-    let mut buf = Vec::new();
-    TERA.render_to("base.html", &context, &mut buf).unwrap();
+/// Pages under `examples/` get runnable code blocks.
+fn is_example(path: &str) -> bool {
+    Path::new(path).starts_with(EXAMPLES_DIR)
+}
+
+/// A markdown page converted to HTML, before it is put in its template.
+struct MarkdownPage<'a> {
+    path: &'a str,
+    /// From the front matter, else the first top level heading, else the path.
+    title: String,
+    front_matter: FrontMatter,
+    rendered: markdown::Rendered,
+}
 
-    buf
+/// The templates and every markdown page rendered with them.
+struct Site {
+    tera: Tera,
+    /// Links shared by all pages: the playground, the top-level markdown
+    /// pages by their `order`, and the examples.
+    nav: Vec<NavLink>,
+    examples: Vec<ExampleLink>,
+    /// Markdown pages by path without the extension.
+    pages: HashMap<String, Page>,
+    /// The examples index by the tag it is filtered by, if any.
+    examples_index: HashMap<Option<String>, Page>,
 }
 
-async fn static_path(uri: Uri) -> impl IntoResponse {
+impl Site {
+    /// Renders every markdown page, failing on the first one that can't be.
+    fn load(config: &PagesConfig) -> anyhow::Result<Self> {
+        let tera = Tera::new(&config.templates)
+            .with_context(|| format!("failed to parse templates in {}", config.templates))?;
+        let mut sources = Vec::new();
+        if config.reload {
+            markdown_on_disk(&config.dir, &config.dir, &mut sources)?;
+        } else {
+            embedded_markdown(&STATIC_DIR, &mut sources);
+        }
+
+        let mut parsed = Vec::with_capacity(sources.len());
+        for (path, content) in &sources {
+            let (front_matter, body) = markdown::split_front_matter(content)
+                .with_context(|| format!("invalid front matter in {path}.md"))?;
+            let rendered = markdown::render(body, is_example(path));
+            let title = front_matter
+                .title
+                .clone()
+                .or_else(|| rendered.title.clone())
+                .unwrap_or_else(|| path.clone());
+            parsed.push(MarkdownPage {
+                path: path.as_str(),
+                title,
+                front_matter,
+                rendered,
+            });
+        }
+
+        let mut top_level: Vec<_> = parsed
+            .iter()
+            .filter(|page| !page.path.contains('/'))
+            .collect();
+        top_level.sort_by_key(|page| (page.front_matter.order.unwrap_or(i64::MAX), page.path));
+        let mut nav = vec![NavLink {
+            path: "/".into(),
+            title: "Playground".into(),
+        }];
+        nav.extend(top_level.into_iter().map(|page| NavLink {
+            path: format!("/{}", page.path),
+            title: page.title.clone(),
+        }));
+
+        let mut examples: Vec<_> = parsed
+            .iter()
+            .filter(|page| Path::new(page.path).parent() == Some(EXAMPLES_DIR.as_ref()))
+            .map(|page| ExampleLink {
+                path: page.path.to_owned(),
+                title: page.title.clone(),
+                tags: page.front_matter.tags.clone(),
+            })
+            .collect();
+        examples.sort_by(|a, b| a.title.cmp(&b.title));
+        if !examples.is_empty() {
+            nav.push(NavLink {
+                path: format!("/{EXAMPLES_DIR}"),
+                title: "Examples".into(),
+            });
+        }
+
+        let mut site = Site {
+            tera,
+            nav,
+            examples,
+            pages: HashMap::with_capacity(parsed.len()),
+            examples_index: HashMap::new(),
+        };
+        for page in &parsed {
+            let rendered = site
+                .render_markdown(page)
+                .with_context(|| format!("failed to render {}.md", page.path))?;
+            site.pages.insert(page.path.to_owned(), rendered);
+        }
+
+        let mut tags: Vec<_> = site
+            .examples
+            .iter()
+            .flat_map(|example| example.tags.iter().cloned())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        let filters = std::iter::once(None).chain(tags.iter().map(Some));
+        for tag in filters {
+            let page = site
+                .render_examples_index(&tags, tag.map(String::as_str))
+                .context("failed to render the examples index")?;
+            site.examples_index.insert(tag.cloned(), page);
+        }
+        Ok(site)
+    }
+
+    fn page(&self, path: &str) -> Option<&Page> {
+        self.pages.get(path)
+    }
+
+    fn render(&self, template: &str, context: &Context) -> anyhow::Result<Bytes> {
+        let html = self
+            .tera
+            .render(template, context)
+            .with_context(|| format!("failed to render {template}"))?;
+        Ok(html.into())
+    }
+
+    /// Context every page rendered through `base.html` needs.
+    fn page_context(&self, path: &str, title: &str) -> Context {
+        let mut context = Context::new();
+        context.insert("nav", &self.nav);
+        context.insert("current", &format!("/{path}"));
+        context.insert("title", title);
+        context.insert("description", &None::<String>);
+        context.insert("toc", &Vec::<markdown::Heading>::new());
+        context
+    }
+
+    /// Pages under `examples/` have a layout of their own.
+    fn render_markdown(&self, page: &MarkdownPage) -> anyhow::Result<Page> {
+        let mut context = self.page_context(page.path, &page.title);
+        context.insert("description", &page.front_matter.description);
+        context.insert("html", &page.rendered.html);
+        context.insert("toc", &page.rendered.toc);
+        let template = if is_example(page.path) {
+            let tags: Vec<_> = page
+                .front_matter
+                .tags
+                .iter()
+                .map(|tag| TagLink::new(tag))
+                .collect();
+            context.insert("tags", &tags);
+            "example.html"
+        } else {
+            "base.html"
+        };
+        Ok(Page::new(self.render(template, &context)?))
+    }
+
+    /// `None` if no example has the tag.
+    fn examples_index(&self, tag: Option<String>) -> Option<&Page> {
+        self.examples_index.get(&tag)
+    }
+
+    /// Lists the examples by title, optionally only those with the given tag.
+    fn render_examples_index(&self, tags: &[String], tag: Option<&str>) -> anyhow::Result<Page> {
+        let shown: Vec<_> = self
+            .examples
+            .iter()
+            .filter(|example| tag.is_none_or(|tag| example.tags.iter().any(|t| t == tag)))
+            .collect();
+
+        let mut context = self.page_context(EXAMPLES_DIR, "Examples");
+        context.insert("examples", &shown);
+        let tags: Vec<_> = tags.iter().map(|tag| TagLink::new(tag)).collect();
+        context.insert("tags", &tags);
+        context.insert("tag", &tag);
+        Ok(Page::new(self.render("examples.html", &context)?))
+    }
+}
+
+/// The playground page, also served for routes handled by the frontend.
+pub fn index() -> impl IntoResponse {
+    STATIC_DIR
+        .get_file("index.html")
+        .map_or(StatusCode::NOT_FOUND.into_response(), file_to_response)
+}
+
+async fn static_path(uri: Uri, headers: HeaderMap) -> impl IntoResponse {
     let path = uri.path().trim_start_matches('/');
     let has_extension = path.split_once('.').is_some();
     let not_found_response = StatusCode::NOT_FOUND.into_response();
@@ -81,16 +450,103 @@ async fn static_path(uri: Uri) -> impl IntoResponse {
     }
 
     if path.is_empty() {
-        return STATIC_DIR
-            .get_file("index.html")
-            .map_or(StatusCode::NOT_FOUND.into_response(), file_to_response);
+        return index().into_response();
     }
 
-    let md = format!("{}.md", path);
-    STATIC_DIR.get_file(md).map_or(not_found_response, |file| {
-        let bytes = convert_md_to_html(file.contents_utf8().unwrap_or_default());
-        bytes_to_response(Full::from(bytes), mime_guess::mime::TEXT_HTML_UTF_8)
-    })
+    let site = match site() {
+        Ok(site) => site,
+        Err(e) => return internal_error(e),
+    };
+    let page = if path == EXAMPLES_DIR {
+        site.examples_index(selected_tag(uri.query()))
+    } else {
+        site.page(path)
+    };
+    page.map_or(not_found_response, |page| page.to_response(&headers))
+}
+
+fn default_readonly() -> bool {
+    true
+}
+
+fn default_height() -> u32 {
+    400
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+/// Read from the query string of an embedded snippet.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct EmbedOptions {
+    #[serde(default = "default_readonly")]
+    pub readonly: bool,
+    /// Run the code as soon as the page loads.
+    #[serde(default)]
+    pub auto_run: bool,
+    #[serde(default)]
+    pub theme: Theme,
+    /// Height of the page in pixels, including the output.
+    #[serde(default = "default_height")]
+    pub height: u32,
+}
+
+impl Default for EmbedOptions {
+    fn default() -> Self {
+        Self {
+            readonly: default_readonly(),
+            auto_run: false,
+            theme: Theme::default(),
+            height: default_height(),
+        }
+    }
+}
+
+impl EmbedOptions {
+    pub fn height(&self) -> u32 {
+        self.height.clamp(MIN_EMBED_HEIGHT, MAX_EMBED_HEIGHT)
+    }
+}
+
+/// Minimal page showing a snippet and its output, meant to be put in an
+/// iframe on other sites. Unlike other pages it may be framed by anyone.
+pub fn embed_page(
+    id: &str,
+    snippet: &Snippet,
+    result: Option<&StoredResult>,
+    options: &EmbedOptions,
+    oembed_url: &str,
+) -> Response<UnsyncBoxBody<Bytes, axum::Error>> {
+    let mut context = Context::new();
+    context.insert("id", id);
+    context.insert("code", &snippet.code);
+    context.insert("crate_type", &snippet.options.crate_type);
+    context.insert("readonly", &options.readonly);
+    context.insert("auto_run", &options.auto_run);
+    context.insert("theme", &options.theme);
+    context.insert("height", &options.height());
+    context.insert("oembed_url", oembed_url);
+    let response = result.map(|result| &result.response);
+    let failed = !matches!(response, Some(HandlerResponse::Success(_)) | None);
+    context.insert("output", response.map_or("", HandlerResponse::text));
+    context.insert("failed", &failed);
+    context.insert("toolchain", &result.map(|result| &result.toolchain));
+
+    let html = match site().and_then(|site| site.render("embed.html", &context)) {
+        Ok(html) => html,
+        Err(e) => return internal_error(e),
+    };
+    let mut response = bytes_to_response(Full::from(html), mime_guess::mime::TEXT_HTML_UTF_8);
+    response
+        .headers_mut()
+        .insert(header::CONTENT_SECURITY_POLICY, ANY_FRAME_ANCESTOR);
+    response
 }
 
 pub fn file_service(browser_max_age: HeaderValue, cdn_max_age: HeaderValue) -> MethodRouter {
@@ -105,3 +561,93 @@ pub fn file_service(browser_max_age: HeaderValue, cdn_max_age: HeaderValue) -> M
         ));
     get(static_handler)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_embed_height() {
+        let height = |height| EmbedOptions {
+            height,
+            ..EmbedOptions::default()
+        };
+        assert_eq!(EmbedOptions::default().height(), 400);
+        assert_eq!(height(0).height(), MIN_EMBED_HEIGHT);
+        assert_eq!(height(u32::MAX).height(), MAX_EMBED_HEIGHT);
+    }
+
+    #[test]
+    fn prerender_pages_from_disk() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("examples"))?;
+        std::fs::write(dir.path().join("guide.md"), "## Setup\n\n# Guide\n")?;
+        std::fs::write(
+            dir.path().join("examples/hello.md"),
+            "+++\ntitle = \"Hello\"\n+++\n\n```rust\nfn main() {}\n```\n",
+        )?;
+        let config = PagesConfig {
+            templates: concat!(env!("CARGO_MANIFEST_DIR"), "/../templates/**/*").to_owned(),
+            reload: true,
+            dir: dir.path().to_owned(),
+        };
+
+        let site = Site::load(&config)?;
+        let guide = site.page("guide").expect("guide is rendered");
+        assert!(std::str::from_utf8(&guide.html)?.contains(">Examples</a>"));
+        // Pages without a title in their front matter go by their first H1 everywhere.
+        let html = std::str::from_utf8(&guide.html)?;
+        assert!(html.contains("<title>Guide · TypeRust</title>"));
+        assert!(html.contains(">Guide</a>"), "{html}");
+        let example = site.page("examples/hello").expect("example is rendered");
+        assert!(std::str::from_utf8(&example.html)?.contains("class=\"runnable\""));
+        let etag = guide.etag.to_str()?;
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert_ne!(guide.etag, example.etag);
+        assert_eq!(Site::load(&config)?.page("guide").unwrap().etag, guide.etag);
+
+        std::fs::write(dir.path().join("broken.md"), "+++\ntitle = 1\n+++\n")?;
+        let error = format!("{:#}", Site::load(&config).err().unwrap());
+        assert!(error.contains("broken.md"), "{error}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn link_and_filter_tags() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("examples"))?;
+        std::fs::write(
+            dir.path().join("examples/rare.md"),
+            "+++\ntitle = \"Rare\"\ntags = [\"a&b c\"]\n+++\n",
+        )?;
+        std::fs::write(dir.path().join("examples/plain.md"), "# Plain\n")?;
+        let config = PagesConfig {
+            templates: concat!(env!("CARGO_MANIFEST_DIR"), "/../templates/**/*").to_owned(),
+            reload: true,
+            dir: dir.path().to_owned(),
+        };
+
+        let site = Site::load(&config)?;
+        let example = site.page("examples/rare").expect("example is rendered");
+        assert!(std::str::from_utf8(&example.html)?.contains("?tag=a%26b+c\""));
+
+        let tag = selected_tag(Some("tag=a%26b+c&tag=other"));
+        assert_eq!(tag.as_deref(), Some("a&b c"));
+        let index = site.examples_index(tag).expect("tagged index is rendered");
+        let index = std::str::from_utf8(&index.html)?;
+        assert!(index.contains(">Rare</a>") && !index.contains(">Plain</a>"));
+        let index = site.examples_index(None).expect("index is rendered");
+        assert!(std::str::from_utf8(&index.html)?.contains(">Plain</a>"));
+        assert!(site.examples_index(Some("unknown".into())).is_none());
+
+        // Only the examples index reads the query string.
+        let response = static_path(
+            Uri::from_static("/global.css?tag=a&tag=b"),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+}
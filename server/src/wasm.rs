@@ -1,20 +1,131 @@
-use std::io::Read;
-use std::io::Seek;
 use std::path::Path;
 
+use crate::cache::{BuildCache, CacheStatus};
 use crate::error::SandboxError;
+use crate::input::{IdleClock, IdleTimeoutReached, InteractiveStdin};
+use crate::output::{BoundedOutput, OutputKind, OutputLimitReached, OutputSink};
 use anyhow::bail;
-use tokio::time::{interval, Duration};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
 use tracing::instrument;
-use tracing::Instrument;
-use wasmtime::Config;
-use wasmtime::Trap;
+use wasmtime::{
+    Config, InstanceAllocationStrategy, PoolConcurrencyLimitError, PoolingAllocationConfig,
+};
 use wasmtime::{Engine, Linker, Module, ResourceLimiter, Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime::{Trap, UpdateDeadline};
 use wasmtime_wasi::preview1::WasiP1Ctx;
+use wasmtime_wasi::I32Exit;
 
-const WASM_MINIMUM_MEMORY_SIZE: u64 = bytesize::KIB * 64 * 17;
-const WASM_INSTANCE_MEMORY_LIMIT: u64 = WASM_MINIMUM_MEMORY_SIZE + bytesize::MB * 100;
-const TICKS_BEFORE_TIMEOUT: u64 = 5;
+pub const WASM_MINIMUM_MEMORY_SIZE: u64 = bytesize::KIB * 64 * 17;
+pub const WASM_INSTANCE_MEMORY_LIMIT: u64 = WASM_MINIMUM_MEMORY_SIZE + bytesize::MB * 100;
+const WASM_PAGE_SIZE: u64 = bytesize::KIB * 64;
+/// Also the time slice after which a guest yields to other tasks.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+/// Exit code of a guest stopped by a trap, which is how panics end on
+/// `wasm32-wasip1`. Same as a process killed by `SIGABRT` on Unix.
+pub const TRAP_EXIT_CODE: i32 = 134;
+
+fn default_pooling() -> bool {
+    true
+}
+
+fn default_max_instances() -> u32 {
+    64
+}
+
+fn default_max_tables() -> u32 {
+    1
+}
+
+fn default_table_elements() -> usize {
+    20_000
+}
+
+/// Read from `ENGINE_*` environment variables.
+#[derive(Deserialize, Debug)]
+pub struct EngineConfig {
+    /// Preallocate instance slots instead of allocating on demand.
+    #[serde(default = "default_pooling")]
+    pub pooling: bool,
+    /// Number of sandboxes that may run at the same time.
+    #[serde(default = "default_max_instances")]
+    pub max_instances: u32,
+    /// Size of each linear memory slot in WASM pages. Defaults to the
+    /// largest memory limit a run may ask for.
+    #[serde(default)]
+    pub memory_pages: Option<u64>,
+    #[serde(default = "default_max_tables")]
+    pub max_tables: u32,
+    #[serde(default = "default_table_elements")]
+    pub table_elements: usize,
+    /// Instruction budget per run. Enables fuel metering when set.
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            pooling: default_pooling(),
+            max_instances: default_max_instances(),
+            memory_pages: None,
+            max_tables: default_max_tables(),
+            table_elements: default_table_elements(),
+            fuel: None,
+        }
+    }
+}
+
+/// Limits applied to a single sandboxed run.
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutionLimits {
+    /// Linear memory in bytes.
+    pub memory: u64,
+    /// Time the guest may spend running. Waiting for input doesn't count.
+    pub timeout: Duration,
+    /// Time the guest may wait for a single chunk of input.
+    pub idle_timeout: Duration,
+    /// Output in bytes. The guest is stopped when it tries to write more.
+    pub output: u64,
+    /// Only honored by engines created with fuel enabled.
+    pub fuel: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResourceUsage {
+    /// Wall time of instantiating and running the guest in seconds.
+    pub elapsed: f32,
+    /// Largest size of the guest's linear memory in bytes.
+    pub peak_memory: usize,
+    pub memory_grow_events: u32,
+    pub stdout_bytes: u64,
+    /// Deterministic measure of the work done by the guest, if metered.
+    pub fuel_consumed: Option<u64>,
+}
+
+/// Streams connected to a running guest besides the captured output.
+#[derive(Default)]
+pub struct GuestIo {
+    /// Command line arguments, starting with the program name.
+    pub args: &'static [&'static str],
+    /// Sees output while the guest runs.
+    pub sink: Option<OutputSink>,
+    /// Feeds the guest's stdin. Without it stdin is empty.
+    pub stdin: Option<mpsc::Receiver<Bytes>>,
+}
+
+pub struct Execution {
+    pub output: String,
+    /// Panic messages and anything else the guest wrote to stderr.
+    pub stderr: String,
+    /// Whether the guest was stopped for exceeding the output limit.
+    pub truncated: bool,
+    /// Zero unless the guest exited with an error or trapped, see [`TRAP_EXIT_CODE`].
+    pub exit_code: i32,
+    pub usage: ResourceUsage,
+}
 
 struct WasmStoreData {
     wasi: WasiP1Ctx,
@@ -24,6 +135,8 @@ struct WasmStoreData {
 struct MemoryLimiter {
     limiter: StoreLimits,
     memory_limit_exceeded: bool,
+    peak_memory: usize,
+    grow_events: u32,
 }
 
 impl MemoryLimiter {
@@ -31,6 +144,8 @@ impl MemoryLimiter {
         Self {
             limiter,
             memory_limit_exceeded: false,
+            peak_memory: 0,
+            grow_events: 0,
         }
     }
 
@@ -42,17 +157,22 @@ impl MemoryLimiter {
 impl ResourceLimiter for MemoryLimiter {
     fn memory_growing(
         &mut self,
-        _current: usize,
+        current: usize,
         desired: usize,
-        _maximum: Option<usize>,
+        maximum: Option<usize>,
     ) -> anyhow::Result<bool> {
-        let is_rejected = self.limiter.memory_growing(_current, desired, _maximum);
-        if let Ok(is_rejected) = is_rejected {
-            if is_rejected {
-                self.memory_limit_exceeded = true;
-            }
+        let is_allowed = self.limiter.memory_growing(current, desired, maximum)?;
+        if !is_allowed {
+            self.memory_limit_exceeded = true;
+            return Ok(false);
+        }
+
+        self.peak_memory = self.peak_memory.max(desired);
+        // The initial allocation at instantiation starts from zero and isn't a grow.
+        if current > 0 {
+            self.grow_events += 1;
         }
-        is_rejected
+        Ok(true)
     }
 
     fn table_growing(
@@ -74,94 +194,183 @@ impl ResourceLimiter for MemoryLimiter {
     }
 }
 
-fn is_deadline_error(err: &Trap) -> bool {
-    err.to_string().starts_with("epoch deadline")
+fn is_deadline_error(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<Trap>(), Some(Trap::Interrupt))
+}
+
+fn is_fuel_error(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<Trap>(), Some(Trap::OutOfFuel))
 }
 
-fn run_wasm_instance(
+fn is_output_limit_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<OutputLimitReached>().is_some()
+}
+
+fn is_idle_timeout_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<IdleTimeoutReached>().is_some()
+}
+
+async fn run_wasm_instance(
     module: &Module,
     engine: &Engine,
     mut store: &mut Store<WasmStoreData>,
 ) -> anyhow::Result<Option<anyhow::Error>> {
     use wasmtime_wasi::preview1;
 
-    let mut linker: Linker<WasmStoreData> = Linker::new(&engine);
-    preview1::add_to_linker_sync(&mut linker, |t| &mut t.wasi)?;
-    let pre = linker.instantiate_pre(&module)?;
-    let instance = pre.instantiate(&mut store)?;
+    let mut linker: Linker<WasmStoreData> = Linker::new(engine);
+    preview1::add_to_linker_async(&mut linker, |t| &mut t.wasi)?;
+    let pre = linker.instantiate_pre(module)?;
+    let instance = pre.instantiate_async(&mut store).await?;
 
     let err = instance
         .get_typed_func::<(), ()>(&mut store, "_start")?
-        .call(&mut store, ())
+        .call_async(&mut store, ())
+        .await
         .err();
 
     Ok(err)
 }
 
-fn run_wasm_timeout(engine: Engine) -> tokio::task::JoinHandle<()> {
-    tokio::task::spawn(async move {
-        let mut interval = interval(Duration::from_millis(1000));
-        for _ in 0..TICKS_BEFORE_TIMEOUT {
-            interval.tick().await;
-            engine.increment_epoch();
+/// Advances the epoch of `engine` for as long as it is alive. Every store sets
+/// its deadline relative to the shared epoch, so concurrent runs don't speed
+/// up each other's timeouts.
+fn spawn_epoch_ticker(engine: &Engine) {
+    let engine = engine.weak();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EPOCH_TICK);
+        match engine.upgrade() {
+            Some(engine) => engine.increment_epoch(),
+            None => break,
         }
-    })
-}
-
-use std::fs::File;
-
-#[cfg(target_os = "linux")]
-fn create_stdout_file() -> std::io::Result<File> {
-    use tempfile::tempfile;
-    tempfile()
-}
-
-#[cfg(target_os = "macos")]
-fn create_stdout_file() -> std::io::Result<File> {
-    use tempfile::tempfile;
-    tempfile()
+    });
 }
 
-fn execute_wasm_instance(module: Module, engine: Engine) -> anyhow::Result<String> {
+/// Runs the module to completion on the current task, yielding between time
+/// slices. Dropping the future stops the guest.
+#[instrument(skip_all, name = "Executing WASM instance", fields(
+    service.name = "typerust"
+))]
+pub async fn execute_wasm(
+    engine: Engine,
+    module: Module,
+    limits: ExecutionLimits,
+    io: GuestIo,
+) -> anyhow::Result<Execution> {
     use wasmtime_wasi::WasiCtxBuilder;
 
-    let mut memfile = create_stdout_file()?;
-    let stdout_file = wasmtime_wasi::OutputFile::new(memfile.try_clone()?);
-    let wasi = WasiCtxBuilder::new().stdout(stdout_file).build_p1();
+    let mut builder = WasiCtxBuilder::new();
+    let mut stdout = BoundedOutput::new(limits.output as usize, OutputKind::Stdout);
+    let mut stderr = BoundedOutput::new(limits.output as usize, OutputKind::Stderr);
+    if let Some(sink) = io.sink {
+        stdout = stdout.with_sink(sink.clone());
+        stderr = stderr.with_sink(sink);
+    }
+    builder.args(io.args);
+    let idle = IdleClock::default();
+    if let Some(receiver) = io.stdin {
+        builder.stdin(InteractiveStdin::new(
+            receiver,
+            idle.clone(),
+            limits.idle_timeout,
+        ));
+    }
+    let wasi = builder
+        .stdout(stdout.clone())
+        .stderr(stderr.clone())
+        .build_p1();
+
+    let mut store = create_wasm_store(&engine, wasi, limits.memory);
+    if let Some(fuel) = limits.fuel {
+        store.set_fuel(fuel)?;
+    }
 
-    let mut store = create_wasm_store(&engine, wasi);
-    store.set_epoch_deadline(TICKS_BEFORE_TIMEOUT);
+    let start = Instant::now();
+    // Works like `epoch_deadline_async_yield_and_update(1)`, letting other
+    // tasks run every tick, but stops the guest once it has been active for
+    // longer than the timeout. Time blocked on stdin doesn't count.
+    store.set_epoch_deadline(1);
+    store.epoch_deadline_callback(move |_| {
+        let active = start.elapsed().saturating_sub(idle.total());
+        if active >= limits.timeout {
+            return Err(Trap::Interrupt.into());
+        }
+        Ok(UpdateDeadline::Yield(1))
+    });
 
-    let timeout = run_wasm_timeout(engine.clone());
-    let err = run_wasm_instance(&module, &engine, &mut store)?;
+    let result = run_wasm_instance(&module, &engine, &mut store).await;
+    let elapsed = start.elapsed();
+    let err = match result {
+        Ok(err) => err,
+        Err(err) => {
+            if err
+                .chain()
+                .any(|cause| cause.is::<PoolConcurrencyLimitError>())
+            {
+                tracing::info!("SandboxError::Busy");
+                bail!(SandboxError::Busy)
+            }
+            return Err(err);
+        }
+    };
 
+    let mut exit_code = 0;
     if let Some(err) = err {
-        if is_deadline_error(&err.downcast::<Trap>()?) {
+        if is_output_limit_error(&err) {
+            tracing::info!("stopped guest at the output limit");
+        }
+
+        if is_deadline_error(&err) || is_idle_timeout_error(&err) {
             tracing::info!("SandboxError::Timeout");
             bail!(SandboxError::Timeout)
         }
 
+        if is_fuel_error(&err) {
+            tracing::info!("SandboxError::FuelExhausted");
+            bail!(SandboxError::FuelExhausted)
+        }
+
         if store.data().memory_limiter.memory_limit_exceeded() {
-            timeout.abort();
             tracing::info!("SandboxError::OOM");
             bail!(SandboxError::OOM)
         }
-    }
 
-    timeout.abort();
-    if let Err(e) = memfile.rewind() {
-        bail!("failed to rewind: {e}")
+        if let Some(I32Exit(code)) = err.downcast_ref::<I32Exit>() {
+            exit_code = *code;
+        } else if !is_output_limit_error(&err) {
+            tracing::info!("guest trapped: {err:#}");
+            exit_code = TRAP_EXIT_CODE;
+        }
     }
 
-    let mut output = String::new();
-    memfile.read_to_string(&mut output)?;
+    let captured = stdout.captured();
+    let captured_stderr = stderr.captured();
+
+    let fuel_consumed = match limits.fuel {
+        Some(fuel) => Some(fuel - store.get_fuel()?),
+        None => None,
+    };
+
+    let memory_limiter = &store.data().memory_limiter;
+    let usage = ResourceUsage {
+        elapsed: elapsed.as_secs_f32(),
+        peak_memory: memory_limiter.peak_memory,
+        memory_grow_events: memory_limiter.grow_events,
+        stdout_bytes: captured.bytes,
+        fuel_consumed,
+    };
 
-    Ok(output)
+    Ok(Execution {
+        output: captured.text,
+        stderr: captured_stderr.text,
+        truncated: captured.truncated || captured_stderr.truncated,
+        exit_code,
+        usage,
+    })
 }
 
-fn create_wasm_store(engine: &Engine, wasi: WasiP1Ctx) -> Store<WasmStoreData> {
+fn create_wasm_store(engine: &Engine, wasi: WasiP1Ctx, memory: u64) -> Store<WasmStoreData> {
     let store_limits = StoreLimitsBuilder::new()
-        .memory_size(WASM_INSTANCE_MEMORY_LIMIT as usize)
+        .memory_size(memory as usize)
         .build();
     let memory_limiter = MemoryLimiter::new(store_limits);
     let mut store = Store::new(
@@ -175,19 +384,101 @@ fn create_wasm_store(engine: &Engine, wasi: WasiP1Ctx) -> Store<WasmStoreData> {
     store
 }
 
-#[instrument(skip_all, name = "Executing WASM instance", fields(
-    service.name = "typerust"
+pub struct LoadedModule {
+    pub module: Module,
+    /// Time spent compiling the module with Cranelift or loading it from cache.
+    pub elapsed: Duration,
+    pub cache: CacheStatus,
+}
+
+async fn compile_module(engine: &Engine, wasm: Vec<u8>) -> anyhow::Result<Module> {
+    let engine = engine.clone();
+    tokio::task::spawn_blocking(move || Module::from_binary(&engine, &wasm)).await?
+}
+
+#[instrument(skip_all, name = "Loading WASM module", fields(
+    service.name = "typerust",
+    module.cache = tracing::field::Empty
 ))]
-pub async fn execute_wasm(engine: Engine, module_path: impl AsRef<Path>) -> anyhow::Result<String> {
-    let module = Module::from_file(&engine, module_path)?;
-    let task_span = tracing::info_span!("Running execution task");
-    tokio::task::spawn_blocking(move || execute_wasm_instance(module, engine))
-        .instrument(task_span)
-        .await?
+pub async fn load_module(
+    engine: &Engine,
+    module_path: impl AsRef<Path>,
+    cache: Option<&BuildCache>,
+) -> anyhow::Result<LoadedModule> {
+    let wasm = tokio::fs::read(module_path).await?;
+    let start = Instant::now();
+
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            let module = compile_module(engine, wasm).await?;
+            return Ok(LoadedModule {
+                module,
+                elapsed: start.elapsed(),
+                cache: record_module_cache_status(CacheStatus::Disabled),
+            });
+        }
+    };
+
+    let key = cache.module_key(engine, &wasm);
+    if let Some(module) = cache.get_module(engine, &key).await {
+        return Ok(LoadedModule {
+            module,
+            elapsed: start.elapsed(),
+            cache: record_module_cache_status(CacheStatus::Hit),
+        });
+    }
+
+    let module = compile_module(engine, wasm).await?;
+    let elapsed = start.elapsed();
+    if let Err(e) = cache.put_module(&key, &module).await {
+        tracing::warn!("failed to store module in cache: {e}");
+    }
+
+    Ok(LoadedModule {
+        module,
+        elapsed,
+        cache: record_module_cache_status(CacheStatus::Miss),
+    })
+}
+
+fn record_module_cache_status(status: CacheStatus) -> CacheStatus {
+    tracing::Span::current().record("module.cache", &status.as_str());
+    status
 }
 
-pub fn create_interruptable_engine() -> Engine {
+pub fn create_interruptable_engine(config: &EngineConfig, max_memory: u64) -> Engine {
     let mut engine_config = Config::new();
+    engine_config.async_support(true);
     engine_config.epoch_interruption(true);
-    Engine::new(&engine_config).expect("failed to initialize wasm engine")
+    engine_config.consume_fuel(config.fuel.is_some());
+
+    if config.pooling {
+        let memory_pages = config
+            .memory_pages
+            .unwrap_or_else(|| max_memory.div_ceil(WASM_PAGE_SIZE));
+        let memory_size = memory_pages * WASM_PAGE_SIZE;
+        assert!(
+            memory_size >= max_memory,
+            "pooled memory slots must fit the maximum memory limit of {max_memory} bytes"
+        );
+
+        // Every slot is reserved upfront, which bounds the memory of all
+        // sandboxes combined to `max_instances * memory_pages`.
+        let mut pooling_config = PoolingAllocationConfig::default();
+        pooling_config
+            .total_core_instances(config.max_instances)
+            .total_memories(config.max_instances)
+            .total_tables(config.max_instances * config.max_tables)
+            .total_stacks(config.max_instances)
+            .max_memories_per_module(1)
+            .max_tables_per_module(config.max_tables)
+            .max_memory_size(memory_size as usize)
+            .table_elements(config.table_elements);
+        engine_config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling_config));
+    }
+
+    let engine = Engine::new(&engine_config).expect("failed to initialize wasm engine");
+    spawn_epoch_ticker(&engine);
+    engine
 }